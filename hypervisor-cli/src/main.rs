@@ -3,9 +3,13 @@
 use crate::rust_structs::il2cpp::{DotNetArray, DotNetDict, DotNetList, DotNetString};
 use crate::rust_structs::{BaseNetworkable, GameObjectManager, PoolableObject, PrefabPreProcess};
 use colored::*;
+use libvirtdma::backend::guests::{list_kvm_guests, VmSelector};
+use libvirtdma::backend::LiveAccess;
 use libvirtdma::proc_kernelinfo::ProcKernelInfo;
+use libvirtdma::vm::binding_patch::Patch;
 use libvirtdma::vm::mlayout::parse_u64;
-use libvirtdma::vm::VMBinding;
+use libvirtdma::vm::patchfile;
+use libvirtdma::vm::{AccessMode, VMBinding};
 use libvirtdma::win::eprocess::{PsProtectedSigner, PsProtectedType};
 use libvirtdma::win::peb_ldr_data::LdrModule;
 use libvirtdma::win::teb::TEB;
@@ -22,25 +26,24 @@ fn kmod_to_file(vm: &VMBinding, cmd: &[String]) {
     }
     let name = &cmd[1];
     match vm.find_kmod(name) {
-        Some(kmod) => {
+        Ok(Some(kmod)) => {
             let mem = vm.dump_kmod_vmem(&kmod);
             match std::fs::write(name, &mem) {
                 Ok(_) => println!("Module dumped to {}", name),
                 Err(e) => println!("Unable to write file: {}", e.to_string()),
             };
         }
-        None => {
-            println!("Unable to find the kernel module of interest");
-        }
+        Ok(None) => println!("Unable to find the kernel module of interest"),
+        Err(e) => println!("Failed to get kernel modules: {}", e),
     };
 }
 
 fn rust_unity_player_module(vm: &VMBinding, rust: &mut ProcKernelInfo, unity_player: &LdrModule) {
     let rust_dirbase = rust.eprocess.Pcb.DirectoryTableBase;
     let module_mem = match vm.dump_module_vmem(rust, unity_player) {
-        Some(mem) => mem,
-        None => {
-            println!("Unable to dump module memory");
+        Ok(mem) => mem,
+        Err(e) => {
+            println!("Unable to dump module memory: {}", e);
             return;
         }
     };
@@ -113,9 +116,9 @@ fn rust_game_assembly_module(vm: &VMBinding, rust: &mut ProcKernelInfo, game_ass
     */
     let dirbase = rust.eprocess.Pcb.DirectoryTableBase;
     let _module_mem = match vm.dump_module_vmem(rust, game_assembly) {
-        Some(mem) => mem,
-        None => {
-            println!("Unable to dump module memory");
+        Ok(mem) => mem,
+        Err(e) => {
+            println!("Unable to dump module memory: {}", e);
             return;
         }
     };
@@ -182,8 +185,10 @@ Process Context Commands:
     whereis               displays which section of which module the PVA $1 falls into
     deref                 grab a 64-bit pointer from expression PVA $1
     transmute
-    pmemdumpall
-    patch
+    pmemdumpall           dump every mapped user range to the directory $1 (default .), or
+                          the ranges of the x64dbg memory map file $1 next to it
+    patch                 write hexstring $2 at PVA $1, saving the bytes it replaces
+                          (pages shared with other processes need `force` as $3)
     vpdisasm
     tebs
     threads
@@ -211,11 +216,37 @@ General Context Commands:
     kernelexports
     kexports
 
+    listvms               list running VMs (pid, name, uuid, RAM size)
+
+    pte                   show the page table entries for PVA $1 (of the kernel with $2 = kernel),
+                          including paged-out, transition, prototype and demand-zero ones
+    vmmap                 list the mapped ranges of the open process (or the kernel, or with $1 = kernel)
+    whophys               list every process and VA that maps PA $1
+
+    vm                    list attached VMs, each with its own process context
+    vm attach             attach another running VM by pid, name or uuid $2
+    vm image              attach the memory image $2
+    vm use                switch to the attached VM named $2
+    vm detach             detach the VM named $2
+
     kmod_to_file:         dump kernel module with the name $1 to disk
 
     memread:              read $2 bytes of physical memory from $1
     mem2file              read $2 bytes of physical memory from $1 to $3
 
+    frozen                run the command $1.. with the guest's vCPUs paused (needs a QMP socket)
+
+    mode                  show whether the VM is read-only (the default) or writable
+    mode ro|rw            forbid or allow writes to the VM's memory
+
+    tlb                   show hits and misses of the address translation cache
+    tlb flush             forget all cached address translations
+
+    patches               list the patches still applied to the VM
+    patches export        save the open process' patches as an x64dbg .1337 file $2
+    patches import        apply the x64dbg .1337 file $2 to the open process, if its original bytes match
+    unpatch               revert patch $1 (or all of them with `unpatch all`)
+
 Other Commands:
     quit | exit:          exit the program
     usage:                print this message\n"#
@@ -303,11 +334,11 @@ fn dispatch_commands(
                     .to_string();
                     let outfile = p.join(format!("{},0x{:x}.bin", name, module.BaseAddress));
                     let modulemem = match vm.dump_module_vmem(info, module) {
-                        None => {
-                            println!("Unable to read module mem for {}", name);
+                        Err(e) => {
+                            println!("Unable to read module mem for {}: {}", name, e);
                             continue;
                         }
-                        Some(m) => m,
+                        Ok(m) => m,
                     };
                     match std::fs::write(&outfile, &modulemem) {
                         Ok(_) => {
@@ -325,15 +356,35 @@ fn dispatch_commands(
         },
         "pmemdumpall" => match context {
             Some(info) => {
+                let dtb = info.eprocess.Pcb.DirectoryTableBase;
                 let p = std::path::Path::new(match parts.get(1) {
-                    None => {
-                        println!(
-                            "usage: pmemdumpall <pathMemSectionMapFile> (after entering a process context)"
-                        );
-                        return None;
-                    }
+                    None => ".",
                     Some(p) => p,
                 });
+                // Without a map file, dump what the page tables map into the directory
+                if p.is_dir() {
+                    let user_space_end = vm.paging_mode().user_space_end();
+                    let mut ranges: Vec<std::ops::Range<u64>> = Vec::new();
+                    for r in vm
+                        .walk_address_space(dtb)
+                        .take_while(|r| r.va.start < user_space_end)
+                    {
+                        match ranges.last_mut() {
+                            Some(last) if last.end == r.va.start => last.end = r.va.end,
+                            _ => ranges.push(r.va),
+                        }
+                    }
+                    for range in ranges.iter() {
+                        let len = range.end - range.start;
+                        print!("Dumping {} bytes from 0x{:x}...", len, range.start);
+                        let output = vm.vreadvec(dtb, range.start, len);
+                        match std::fs::write(p.join(format!("{:x}.bin", range.start)), output) {
+                            Ok(_) => println!("OK"),
+                            Err(e) => println!("ERR({})", e.to_string()),
+                        };
+                    }
+                    return None;
+                }
                 let parent_dir = match p.parent() {
                     Some(pa) => pa,
                     None => {
@@ -355,7 +406,6 @@ fn dispatch_commands(
                         return None;
                     }
                 };
-                let dtb = info.eprocess.Pcb.DirectoryTableBase;
                 for (begin, section) in layout.sections.iter() {
                     let len = section.range.end - section.range.start;
                     print!("Dumping {} bytes from 0x{:x}...", len, begin);
@@ -367,9 +417,104 @@ fn dispatch_commands(
                 }
             }
             None => println!(
-                "usage: pmemdumpall <pathMemSectionMapFile> (after entering a process context)"
+                "usage: pmemdumpall [<pathMemSectionMapFile> | <outputDir>] (after entering a process context)"
             ),
         },
+        "pte" => {
+            let va = match parts.get(1).and_then(|va| parse_u64(va, false)) {
+                Some(va) => va,
+                None => {
+                    println!("usage: pte <hVA> [kernel]");
+                    return None;
+                }
+            };
+            let dtb = match (parts.get(2).map(String::as_str), context) {
+                (None, Some(info)) => info.eprocess.Pcb.DirectoryTableBase,
+                _ => vm.initial_process.dirbase,
+            };
+            let translation = match vm.translate_detailed(dtb, va) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("Unable to walk the page tables: {}", e);
+                    return None;
+                }
+            };
+            for step in translation.steps.iter() {
+                println!(
+                    "{} @ 0x{:x}: 0x{:016x} {:?} {}",
+                    ["PTE", "PDE", "PDPTE", "PML4E", "PML5E"][step.level as usize - 1],
+                    step.address,
+                    step.entry,
+                    step.state,
+                    step.flags()
+                );
+            }
+            match translation.pa {
+                Some(pa) => println!(
+                    "0x{:x} -> 0x{:x} ({} KiB page)",
+                    va,
+                    pa,
+                    translation.page_size >> 10
+                ),
+                None => println!("0x{:x} is not in RAM", va),
+            }
+        }
+        "vmmap" => {
+            let (dtb, kernel) = match (parts.get(1).map(String::as_str), context) {
+                (Some("kernel"), _) | (None, None) => (vm.initial_process.dirbase, true),
+                (None, Some(info)) => (info.eprocess.Pcb.DirectoryTableBase, false),
+                _ => {
+                    println!("usage: vmmap [kernel]");
+                    return None;
+                }
+            };
+            let user_space_end = vm.paging_mode().user_space_end();
+            let ranges = vm
+                .walk_address_space(dtb)
+                .skip_while(|r| kernel && r.va.start < user_space_end)
+                .take_while(|r| kernel || r.va.start < user_space_end);
+            let mut total = 0u64;
+            for r in ranges {
+                total += r.va.end.wrapping_sub(r.va.start);
+                println!(
+                    "0x{:016x}-0x{:016x} {:>12x} {} {} pa 0x{:x}",
+                    r.va.start,
+                    r.va.end,
+                    r.va.end.wrapping_sub(r.va.start),
+                    r.permissions(),
+                    match r.page_size {
+                        0x1000 => "4K",
+                        0x20_0000 => "2M",
+                        0x40_0000 => "4M",
+                        _ => "1G",
+                    },
+                    r.pa
+                );
+            }
+            println!("{} MiB mapped", total >> 20);
+        }
+        "whophys" => {
+            let pa = match parts.get(1).and_then(|pa| parse_u64(pa, false)) {
+                Some(pa) => pa,
+                None => {
+                    println!("usage: whophys <hPA>");
+                    return None;
+                }
+            };
+            let found = vm.reverse_translate(pa);
+            if found.is_empty() {
+                println!("0x{:x} is not mapped by any process", pa);
+            }
+            for m in found.iter() {
+                println!(
+                    "{:>6} {:<24} 0x{:016x} {}",
+                    m.pid,
+                    m.name,
+                    m.va,
+                    m.range.permissions()
+                );
+            }
+        }
         "transmute" | "tr" | "struct" => match context {
             Some(info) => {
                 if parts.len() != 3 {
@@ -599,11 +744,11 @@ fn dispatch_commands(
                 let dtb = info.eprocess.Pcb.DirectoryTableBase;
                 for module in vm.get_process_modules(info).iter() {
                     let data = match vm.dump_module_vmem(info, module) {
-                        None => {
-                            println!("Unable to read module mem");
+                        Err(e) => {
+                            println!("Unable to read module mem: {}", e);
                             return None;
                         }
-                        Some(m) => m,
+                        Ok(m) => m,
                     };
                     match VMBinding::pmemmem(&data, &keyword) {
                         Ok(results) => {
@@ -704,11 +849,11 @@ fn dispatch_commands(
                 println!("Found MessageBoxA at 0x{:x}", va_msgboxa);
 
                 let main_module_mem = match vm.dump_module_vmem(info, base_module) {
-                    None => {
-                        println!("Unable to read module mem");
+                    Err(e) => {
+                        println!("Unable to read module mem: {}", e);
                         return None;
                     }
-                    Some(m) => m,
+                    Ok(m) => m,
                 };
                 let secretaddr = base_module.BaseAddress
                     + match VMBinding::pmemmem(&main_module_mem, "736563726574") {
@@ -754,15 +899,19 @@ fn dispatch_commands(
                     body.len()
                 );
                 libvirtdma::disasm(&body, 0);
-                vm.vwrite(dirbase, patchaddr, &body);
-                println!("Patched");
+                let force = parts.get(1).map(String::as_str) == Some("force");
+                match vm.patch(dirbase, patchaddr, &body, force) {
+                    Ok(id) => println!("Patched (#{})", id),
+                    Err(e) => println!("Failed to patch: {}", e),
+                }
             }
-            None => println!("usage: autopatch (after entering a process context)"),
+            None => println!("usage: autopatch [force] (after entering a process context)"),
         },
         "patch" => match context {
             Some(info) => {
-                if parts.len() != 3 {
-                    println!("usage: patch <hVA> <hexReplacement>");
+                let force = parts.len() == 4 && parts[3] == "force";
+                if parts.len() != 3 && !force {
+                    println!("usage: patch <hVA> <hexReplacement> [force]");
                     return None;
                 }
                 let hVA = match parse_u64(&parts[1], false) {
@@ -782,15 +931,26 @@ fn dispatch_commands(
                         return None;
                     }
                 };
-                vm.vwrite(info.eprocess.Pcb.DirectoryTableBase, hVA, &replacement);
-                println!("Performed patch at module offset 0x{:x}", hVA);
+                match vm.patch(
+                    info.eprocess.Pcb.DirectoryTableBase,
+                    hVA,
+                    &replacement,
+                    force,
+                ) {
+                    Ok(id) => println!("Performed patch #{} at module offset 0x{:x}", id, hVA),
+                    Err(e) => println!("Failed to patch: {}", e),
+                }
             }
             None => {
-                println!("usage: patch <hVA> <hexReplacement> (after entering a process context")
+                println!("usage: patch <hVA> <hexReplacement> [force] (after entering a process context")
             }
         },
         "winexports" | "kernelexports" | "kexports" => vm.list_kernel_exports(),
-        "listkmod" | "listkmods" => vm.list_kmods(),
+        "listkmod" | "listkmods" => {
+            if let Err(e) = vm.list_kmods() {
+                println!("Failed to list kernel modules: {}", e);
+            }
+        }
         "listproc" | "listprocs" | "listprocess" | "listprocesses" => vm.list_processes(true),
         "close" => {
             return match context {
@@ -846,19 +1006,23 @@ fn dispatch_commands(
                     println!("usage: setprotected <true|false>")
                 } else {
                     if "true".eq(&parts[1]) {
-                        vm.set_process_security(
+                        match vm.set_process_security(
                             info,
                             PsProtectedType::Protected,
                             PsProtectedSigner::WinTcb,
-                        );
-                        println!("Enabled Protection");
+                        ) {
+                            Ok(()) => println!("Enabled Protection"),
+                            Err(e) => println!("Failed to enable protection: {}", e),
+                        }
                     } else if "false".eq(&parts[1]) {
-                        vm.set_process_security(
+                        match vm.set_process_security(
                             info,
                             PsProtectedType::None,
                             PsProtectedSigner::None,
-                        );
-                        println!("Disabled Protection");
+                        ) {
+                            Ok(()) => println!("Disabled Protection"),
+                            Err(e) => println!("Failed to disable protection: {}", e),
+                        }
                     } else {
                         println!("usage: setprotected <true|false>");
                     }
@@ -1105,7 +1269,7 @@ fn dispatch_commands(
                 };
             }
         }
-        "quit" | "exit" => std::process::exit(0),
+        "quit" | "exit" => return Some(DispatchCommandReturnAction::Quit),
         "kmod_to_file" => kmod_to_file(&vm, &parts),
         "memread" => {
             if parts.len() != 3 {
@@ -1129,6 +1293,117 @@ fn dispatch_commands(
                 hexdump::hexdump(&data);
             }
         }
+        "frozen" => {
+            if parts.len() < 2 {
+                println!("usage: frozen <command> [args...]");
+            } else {
+                // Dropped after the command, resuming the guest
+                let _guard = match vm.freeze() {
+                    Ok(g) => g,
+                    Err(e) => {
+                        println!("Unable to freeze the VM: {}", e);
+                        return None;
+                    }
+                };
+                return dispatch_commands(vm, parts[1..].to_vec(), context);
+            }
+        }
+        "listvms" => {
+            let guests = match list_kvm_guests() {
+                Ok(g) => g,
+                Err(e) => {
+                    println!("Unable to list VMs: {}", e);
+                    return None;
+                }
+            };
+            for guest in guests.iter() {
+                println!(
+                    "pid {:<8} name {:<24} uuid {:<36} ram {}",
+                    guest.pid,
+                    guest.name.as_ref().map(String::as_str).unwrap_or("-"),
+                    guest.uuid.as_ref().map(String::as_str).unwrap_or("-"),
+                    match guest.ram_size {
+                        Some(size) => format!("{} MiB", size >> 20),
+                        None => "-".to_string(),
+                    }
+                );
+            }
+        }
+        "mode" => match parts.get(1).map(String::as_str) {
+            None => println!(
+                "{}",
+                match vm.access_mode() {
+                    AccessMode::ReadOnly => "read-only",
+                    AccessMode::ReadWrite => "read-write",
+                }
+            ),
+            Some("ro") => {
+                vm.set_access_mode(AccessMode::ReadOnly);
+                println!("Guest memory is now read-only");
+            }
+            Some("rw") => {
+                vm.set_access_mode(AccessMode::ReadWrite);
+                println!(
+                    "Guest memory is now writable, patch/autopatch/setprotected will modify it"
+                );
+            }
+            Some(_) => println!("usage: mode [ro|rw]"),
+        },
+        "tlb" => match parts.get(1).map(String::as_str) {
+            None => {
+                let (hits, misses) = vm.tlb_stats();
+                println!("{} translation cache hits, {} misses", hits, misses);
+            }
+            Some("flush") => vm.flush_tlb(),
+            Some(_) => println!("usage: tlb [flush]"),
+        },
+        "patches" => match (parts.get(1).map(String::as_str), parts.get(2), context) {
+            (None, _, _) => print_patches(&vm.patches()),
+            (Some("export"), Some(path), Some(info)) => {
+                let patches = vm.export_patches(info);
+                match std::fs::write(path, patchfile::format_1337(&patches)) {
+                    Ok(()) => println!("Exported {} patched range(s) to '{}'", patches.len(), path),
+                    Err(e) => println!("Error while writing to file '{}': {}", path, e),
+                }
+            }
+            (Some("import"), Some(path), Some(info)) => {
+                let patches = match std::fs::read_to_string(path) {
+                    Ok(s) => match patchfile::parse_1337(&s) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            println!("Unable to parse '{}': {}", path, e);
+                            return None;
+                        }
+                    },
+                    Err(e) => {
+                        println!("Unable to read '{}': {}", path, e);
+                        return None;
+                    }
+                };
+                let force = parts.get(3).map(String::as_str) == Some("force");
+                match vm.import_patches(info, &patches, force) {
+                    Ok(ids) => println!("Applied {} patch(es) from '{}'", ids.len(), path),
+                    Err(e) => println!("Failed to import: {}", e),
+                }
+            }
+            _ => println!(
+                "usage: patches [export <file.1337> | import <file.1337> [force]] (export/import after entering a process context)"
+            ),
+        },
+        "unpatch" => match parts.get(1).map(String::as_str) {
+            Some("all") => match vm.unpatch_all() {
+                Ok(n) => println!("Reverted {} patch(es)", n),
+                Err(e) => println!("Failed to revert: {}", e),
+            },
+            Some(id) => match id.parse::<u64>() {
+                Ok(id) => match vm.unpatch(id) {
+                    Ok(()) => println!("Reverted patch #{}", id),
+                    Err(e) => println!("Failed to revert: {}", e),
+                },
+                Err(_) => println!("usage: unpatch <id>|all"),
+            },
+            None => println!("usage: unpatch <id>|all"),
+        },
         "help" | "usage" => show_usage(),
         _ => {
             println!("Unknown command: {:?}", parts);
@@ -1143,19 +1418,292 @@ enum DispatchCommandReturnAction {
     EnterProcessContext(ProcKernelInfo),
     ExitContext,
     EnterKernelContext,
+    Quit,
+}
+
+fn print_patches(patches: &[Patch]) {
+    for p in patches.iter() {
+        println!(
+            "#{:<4} dtb 0x{:x} va 0x{:x} pa 0x{:x} len {} original {}",
+            p.id,
+            p.dirbase,
+            p.address,
+            p.physical.first().map(|r| r.start).unwrap_or(0),
+            p.patched.len(),
+            hex::encode(&p.original)
+        );
+    }
+}
+
+/// Every VM attached during this run, for the exit report.
+type AttachedVms = std::sync::Arc<std::sync::Mutex<Vec<(String, std::sync::Weak<VMBinding>)>>>;
+
+/// Lists the patches still applied so nothing is left in a guest unnoticed.
+fn report_outstanding_patches(attached: &AttachedVms) {
+    for (name, vm) in attached.lock().unwrap().iter() {
+        let patches = match vm.upgrade() {
+            Some(vm) => vm.patches(),
+            None => continue,
+        };
+        if !patches.is_empty() {
+            println!(
+                "{} patch(es) still applied to {} (`unpatch all` reverts them):",
+                patches.len(),
+                name
+            );
+            print_patches(&patches);
+        }
+    }
+}
+
+const USAGE: &str = "usage: hypervisor-cli [--image <memoryImage>] [--access kmod|shared|process_vm|procmem] [--qmp <socket>] [--vm <pid|name|uuid>]...";
+
+struct CliArgs {
+    image: Option<String>,
+    access: Option<LiveAccess>,
+    qmp: Option<String>,
+    vms: Vec<VmSelector>,
+}
+
+fn parse_args() -> CliArgs {
+    let mut args = CliArgs {
+        image: None,
+        access: None,
+        qmp: None,
+        vms: Vec::new(),
+    };
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_ref() {
+            "--image" => match it.next() {
+                Some(path) => args.image = Some(path),
+                None => {
+                    println!("{}", USAGE);
+                    std::process::exit(1);
+                }
+            },
+            "--vm" => match it.next() {
+                Some(sel) => args.vms.push(VmSelector::parse(&sel)),
+                None => {
+                    println!("{}", USAGE);
+                    std::process::exit(1);
+                }
+            },
+            "--qmp" => match it.next() {
+                Some(path) => args.qmp = Some(path),
+                None => {
+                    println!("{}", USAGE);
+                    std::process::exit(1);
+                }
+            },
+            "--access" => {
+                args.access = match it.next().as_ref().map(String::as_str) {
+                    Some("kmod") => Some(LiveAccess::KernelModule),
+                    Some("shared") => Some(LiveAccess::SharedFile),
+                    Some("process_vm") => Some(LiveAccess::ProcessVm),
+                    Some("procmem") => Some(LiveAccess::ProcMem),
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            unk => {
+                println!("Unknown argument: {}", unk);
+                println!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+    args
+}
+
+/// One attached VM (or image) and the process context open in it.
+struct Session {
+    name: String,
+    vm: std::sync::Arc<VMBinding>,
+    open_process: Option<ProcKernelInfo>,
+}
+
+impl Session {
+    fn attach(sel: Option<&VmSelector>, access: Option<LiveAccess>) -> libvirtdma::Result<Session> {
+        let guest = list_kvm_guests()?.into_iter().find(|g| match sel {
+            Some(sel) => sel.matches(g),
+            None => true,
+        });
+        let name = match &guest {
+            Some(g) => match &g.name {
+                Some(n) => n.clone(),
+                None => format!("pid{}", g.pid),
+            },
+            None => "vm".to_string(),
+        };
+        // Sessions start read-only, `mode rw` allows writes
+        let mut builder = VMBinding::builder().access_mode(AccessMode::ReadOnly);
+        if let Some(sel) = sel {
+            builder = builder.vm(sel.clone());
+        }
+        if let Some(access) = access {
+            builder = builder.access(access);
+        }
+        Ok(Session {
+            name,
+            vm: std::sync::Arc::new(builder.build()?),
+            open_process: None,
+        })
+    }
+
+    fn open_image(path: &str) -> libvirtdma::Result<Session> {
+        let name = match std::path::Path::new(path).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => path.to_string(),
+        };
+        Ok(Session {
+            name,
+            vm: std::sync::Arc::new(
+                VMBinding::builder()
+                    .memory(libvirtdma::backend::open_image(path)?)
+                    .access_mode(AccessMode::ReadOnly)
+                    .build()?,
+            ),
+            open_process: None,
+        })
+    }
+
+    fn prompt(&self, show_name: bool) -> String {
+        let mut prompt = String::new();
+        if show_name {
+            prompt.push_str(&format!("[{}]", self.name));
+        }
+        if self.vm.access_mode() == AccessMode::ReadWrite {
+            prompt.push_str("[rw]");
+        }
+        if let Some(pki) = &self.open_process {
+            prompt.push_str(&format!("[pid={}]", pki.eprocess.UniqueProcessId));
+        }
+        prompt
+    }
+}
+
+/// Handles `vm ...`, which manages the attached sessions rather than acting on one.
+fn dispatch_vm_command(
+    sessions: &mut Vec<Session>,
+    current: &mut usize,
+    parts: &[String],
+    access: Option<LiveAccess>,
+    attached: &AttachedVms,
+) {
+    let add = |sessions: &mut Vec<Session>,
+               current: &mut usize,
+               session: libvirtdma::Result<Session>| match session {
+        Ok(mut session) => {
+            if sessions.iter().any(|s| s.name == session.name) {
+                session.name = format!("{}-{}", session.name, sessions.len());
+            }
+            println!("Attached {}, now using it", session.name);
+            attached
+                .lock()
+                .unwrap()
+                .push((session.name.clone(), std::sync::Arc::downgrade(&session.vm)));
+            sessions.push(session);
+            *current = sessions.len() - 1;
+        }
+        Err(e) => println!("Failed to bind: {}", e),
+    };
+    match (parts.get(1).map(String::as_str), parts.get(2)) {
+        (Some("list"), None) | (None, None) => {
+            for (i, s) in sessions.iter().enumerate() {
+                println!(
+                    "{} {:<24} {}",
+                    if i == *current { "*" } else { " " },
+                    s.name,
+                    match &s.open_process {
+                        Some(pki) => format!("pid={}", pki.eprocess.UniqueProcessId),
+                        None => "".to_string(),
+                    }
+                );
+            }
+        }
+        (Some("attach"), Some(sel)) => add(
+            sessions,
+            current,
+            Session::attach(Some(&VmSelector::parse(sel)), access),
+        ),
+        (Some("image"), Some(path)) => add(sessions, current, Session::open_image(path)),
+        (Some("use"), Some(name)) => match sessions.iter().position(|s| &s.name == name) {
+            Some(i) => *current = i,
+            None => println!("No attached VM named {} (see `vm list`)", name),
+        },
+        (Some("detach"), Some(name)) => match sessions.iter().position(|s| &s.name == name) {
+            Some(_) if sessions.len() == 1 => println!("Can't detach the only VM, use `quit`"),
+            Some(i) => {
+                let outstanding = sessions[i].vm.patches().len();
+                if outstanding > 0 {
+                    println!("{} patch(es) stay applied to {}", outstanding, name);
+                }
+                sessions.remove(i);
+                if *current >= i && *current > 0 {
+                    *current -= 1;
+                }
+                println!("Detached {}, using {}", name, sessions[*current].name);
+            }
+            None => println!("No attached VM named {} (see `vm list`)", name),
+        },
+        _ => println!(
+            "usage: vm [list | attach <pid|name|uuid> | image <path> | use <name> | detach <name>]"
+        ),
+    }
 }
 
 #[macro_use]
 extern crate rouille;
 
 fn main() {
+    let args = parse_args();
+    // Progress and warnings from libvirtdma; RUST_LOG=debug for more
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_timestamp(None)
+        .init();
+
+    let attached: AttachedVms = Default::default();
+    let handler_attached = std::sync::Arc::clone(&attached);
     ctrlc::set_handler(move || {
         println!("Exiting gracefully...");
+        report_outstanding_patches(&handler_attached);
         std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
 
-    let vm = std::sync::Arc::new(VMBinding::new().expect("failed to bind"));
+    let bound = |session: libvirtdma::Result<Session>| match session {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Failed to bind: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut sessions: Vec<Session> = Vec::new();
+    if let Some(path) = &args.image {
+        sessions.push(bound(Session::open_image(path)));
+    }
+    for sel in args.vms.iter() {
+        sessions.push(bound(Session::attach(Some(sel), args.access)));
+    }
+    if sessions.is_empty() {
+        sessions.push(bound(Session::attach(None, args.access)));
+    }
+    if let Some(qmp) = &args.qmp {
+        // Nothing else holds the binding yet
+        if let Some(vm) = std::sync::Arc::get_mut(&mut sessions[0].vm) {
+            vm.monitor = Some(std::path::PathBuf::from(qmp));
+        }
+    }
+    for s in sessions.iter() {
+        attached
+            .lock()
+            .unwrap()
+            .push((s.name.clone(), std::sync::Arc::downgrade(&s.vm)));
+    }
+    let mut current = 0usize;
     let histfile = format!(
         "{}/.lvdmacli_hist",
         match dirs::home_dir() {
@@ -1164,7 +1712,8 @@ fn main() {
         }
     );
 
-    let apivmref = std::sync::Arc::clone(&vm);
+    // The HTTP API serves the VM attached at startup
+    let apivmref = std::sync::Arc::clone(&sessions[0].vm);
     let _api_handle = std::thread::spawn(move || {
         rouille::start_server("0.0.0.0:2222", move |request| {
             let reqvmref = std::sync::Arc::clone(&apivmref);
@@ -1207,35 +1756,42 @@ fn main() {
             .unwrap();
     };
 
-    set_interface_text("");
+    set_interface_text(&sessions[current].prompt(sessions.len() > 1));
 
-    let mut open_process: Option<ProcKernelInfo> = None;
     while let ReadResult::Input(line) = interface.read_line().unwrap() {
         match shlex::split(&line) {
             None => println!("Empty/None command invalid"),
             Some(parts) => {
                 if parts.is_empty() {
                     println!("Empty command invalid")
+                } else if parts[0] == "vm" {
+                    dispatch_vm_command(
+                        &mut sessions,
+                        &mut current,
+                        &parts,
+                        args.access,
+                        &attached,
+                    );
                 } else {
-                    if let Some(context_action) = dispatch_commands(&vm, parts, &mut open_process) {
+                    let session = &mut sessions[current];
+                    if let Some(context_action) =
+                        dispatch_commands(&session.vm, parts, &mut session.open_process)
+                    {
                         match context_action {
                             DispatchCommandReturnAction::EnterKernelContext => {
                                 println!("not implemented yet");
                             }
                             DispatchCommandReturnAction::ExitContext => {
-                                set_interface_text("");
-                                open_process = None;
+                                session.open_process = None;
                             }
                             DispatchCommandReturnAction::EnterProcessContext(pki) => {
-                                set_interface_text(&format!(
-                                    "[pid={}]",
-                                    pki.eprocess.UniqueProcessId
-                                ));
-                                open_process = Some(pki);
+                                session.open_process = Some(pki);
                             }
+                            DispatchCommandReturnAction::Quit => break,
                         }
                     }
                 }
+                set_interface_text(&sessions[current].prompt(sessions.len() > 1));
             }
         }
         interface.add_history_unique(line);
@@ -1243,4 +1799,5 @@ fn main() {
             eprintln!("Could not save history file {}: {}", histfile.clone(), e);
         }
     }
+    report_outstanding_patches(&attached);
 }
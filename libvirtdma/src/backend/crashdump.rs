@@ -0,0 +1,264 @@
+use crate::backend::regions::{PhysRegion, RegionMap};
+use crate::backend::{KernelHints, PhysicalMemory};
+use crate::error::{self, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{Mmap, MmapOptions};
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+pub const DUMP_SIGNATURE: [u8; 4] = *b"PAGE";
+const DUMP_VALID_DUMP64: [u8; 4] = *b"DU64";
+const DUMP_HEADER64_SIZE: u64 = 0x2000;
+const PAGE_SIZE: u64 = 0x1000;
+
+// _DUMP_HEADER64 field offsets
+const OFFSET_DIRECTORY_TABLE_BASE: usize = 0x10;
+const OFFSET_PS_LOADED_MODULE_LIST: usize = 0x20;
+const OFFSET_PS_ACTIVE_PROCESS_HEAD: usize = 0x28;
+const OFFSET_NUMBER_OF_RUNS: usize = 0x88;
+const OFFSET_RUNS: usize = 0x98;
+// End of the PHYSICAL_MEMORY_DESCRIPTOR buffer (0x2c0 bytes from NumberOfRuns)
+const OFFSET_RUNS_END: usize = 0x348;
+const OFFSET_DUMP_TYPE: usize = 0xf98;
+
+// _SUMMARY_DUMP64 field offsets, relative to the end of _DUMP_HEADER64
+const OFFSET_SUMMARY_HEADER_SIZE: usize = 0x20;
+const OFFSET_SUMMARY_BITMAP_SIZE: usize = 0x28;
+const OFFSET_SUMMARY_BITMAP: usize = 0x38;
+
+const DUMP_TYPE_FULL: u32 = 1;
+const DUMP_TYPE_BITMAP_FULL: u32 = 5;
+const DUMP_TYPE_BITMAP_KERNEL: u32 = 6;
+
+/// 64-bit Windows kernel crash dump (`MEMORY.DMP`), either a full dump described by
+/// the physical memory runs in the header or a bitmap dump where a page bitmap
+/// selects which physical pages follow the summary header.
+pub struct CrashDump {
+    map: Mmap,
+    regions: RegionMap,
+    hints: KernelHints,
+}
+
+impl CrashDump {
+    pub fn open<P: AsRef<Path>>(path: P) -> error::Result<CrashDump> {
+        let file = File::open(path.as_ref()).map_err(|e| Error::io(&path, e))?;
+        let map = unsafe { MmapOptions::new().map(&file) }.map_err(|e| Error::io(&path, e))?;
+        let runs = Self::parse_runs(&map).map_err(|e| {
+            Error::Image(format!(
+                "Unable to parse crash dump {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+        let base = map.as_ptr() as u64;
+        let regions = RegionMap::new(
+            runs.iter()
+                .map(|(pa, offset, size)| PhysRegion {
+                    start: *pa,
+                    size: *size,
+                    host: base + *offset,
+                    mapping: 0,
+                })
+                .collect(),
+        );
+        let hints = KernelHints {
+            dtb: LittleEndian::read_u64(&map[OFFSET_DIRECTORY_TABLE_BASE..]),
+            ps_loaded_module_list: LittleEndian::read_u64(&map[OFFSET_PS_LOADED_MODULE_LIST..]),
+            ps_active_process_head: LittleEndian::read_u64(&map[OFFSET_PS_ACTIVE_PROCESS_HEAD..]),
+        };
+        Ok(CrashDump {
+            map,
+            regions,
+            hints,
+        })
+    }
+
+    pub fn regions(&self) -> &RegionMap {
+        &self.regions
+    }
+
+    /// Returns (physical address, file offset, size) for every contiguous run of pages.
+    fn parse_runs(data: &[u8]) -> Result<Vec<(u64, u64, u64)>, String> {
+        if (data.len() as u64) < DUMP_HEADER64_SIZE {
+            return Err("file is smaller than a dump header".to_string());
+        }
+        if data[0..4] != DUMP_SIGNATURE {
+            return Err("missing PAGE signature".to_string());
+        }
+        if data[4..8] != DUMP_VALID_DUMP64 {
+            return Err("only 64-bit (DU64) dumps are supported".to_string());
+        }
+        let runs = match LittleEndian::read_u32(&data[OFFSET_DUMP_TYPE..]) {
+            DUMP_TYPE_FULL => Self::parse_full_runs(data)?,
+            DUMP_TYPE_BITMAP_FULL | DUMP_TYPE_BITMAP_KERNEL => Self::parse_bitmap_runs(data)?,
+            t => return Err(format!("unsupported dump type {}", t)),
+        };
+        for (pa, offset, size) in runs.iter() {
+            match offset.checked_add(*size) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => {
+                    return Err(format!(
+                        "pages for 0x{:x} point past the end of the file",
+                        pa
+                    ))
+                }
+            }
+        }
+        Ok(runs)
+    }
+
+    fn parse_full_runs(data: &[u8]) -> Result<Vec<(u64, u64, u64)>, String> {
+        let run_count = LittleEndian::read_u32(&data[OFFSET_NUMBER_OF_RUNS..]) as usize;
+        if run_count > (OFFSET_RUNS_END - OFFSET_RUNS) / 0x10 {
+            return Err(format!("implausible number of memory runs ({})", run_count));
+        }
+        let mut runs = Vec::with_capacity(run_count);
+        let mut file_offset = DUMP_HEADER64_SIZE;
+        for i in 0..run_count {
+            let run = &data[OFFSET_RUNS + i * 0x10..];
+            let base_page = LittleEndian::read_u64(run);
+            let page_count = LittleEndian::read_u64(&run[8..]);
+            let (pa, size) = match (
+                base_page.checked_mul(PAGE_SIZE),
+                page_count.checked_mul(PAGE_SIZE),
+            ) {
+                (Some(pa), Some(size)) => (pa, size),
+                _ => return Err(format!("memory run {} is out of range", i)),
+            };
+            runs.push((pa, file_offset, size));
+            file_offset = match file_offset.checked_add(size) {
+                Some(o) => o,
+                None => return Err(format!("memory run {} is out of range", i)),
+            };
+        }
+        Ok(runs)
+    }
+
+    fn parse_bitmap_runs(data: &[u8]) -> Result<Vec<(u64, u64, u64)>, String> {
+        let summary = &data[DUMP_HEADER64_SIZE as usize..];
+        if summary.len() < OFFSET_SUMMARY_BITMAP
+            || summary[0..4] != *b"SDMP" && summary[0..4] != *b"FDMP"
+        {
+            return Err("missing summary dump header".to_string());
+        }
+        let mut file_offset = LittleEndian::read_u64(&summary[OFFSET_SUMMARY_HEADER_SIZE..]);
+        let bit_count = LittleEndian::read_u64(&summary[OFFSET_SUMMARY_BITMAP_SIZE..]);
+        let bitmap = match bit_count
+            .checked_add(7)
+            .and_then(|bits| (OFFSET_SUMMARY_BITMAP as u64).checked_add(bits / 8))
+            .and_then(|end| summary.get(OFFSET_SUMMARY_BITMAP..end as usize))
+        {
+            Some(b) => b,
+            None => return Err("truncated page bitmap".to_string()),
+        };
+
+        // Coalesce consecutive present pages; they are stored back to back in the file
+        let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+        for pfn in 0..bit_count {
+            if bitmap[(pfn / 8) as usize] & (1 << (pfn % 8)) == 0 {
+                continue;
+            }
+            let pa = pfn * PAGE_SIZE;
+            match runs.last_mut() {
+                Some((start, _, size)) if *start + *size == pa => *size += PAGE_SIZE,
+                _ => runs.push((pa, file_offset, PAGE_SIZE)),
+            }
+            file_offset = match file_offset.checked_add(PAGE_SIZE) {
+                Some(o) => o,
+                None => return Err("page data offset is out of range".to_string()),
+            };
+        }
+        Ok(runs)
+    }
+}
+
+impl PhysicalMemory for CrashDump {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let base = self.map.as_ptr() as u64;
+        let map = &self.map;
+        self.regions.walk(addr, buf.len(), |host, offset, len| {
+            let file_offset = (host - base) as usize;
+            buf[offset..offset + len].copy_from_slice(&map[file_offset..file_offset + len]);
+        })
+    }
+
+    fn phys_write(&self, _addr: u64, _buf: &[u8]) -> bool {
+        false
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn size(&self) -> u64 {
+        self.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.regions.holes()
+    }
+
+    fn kernel_hints(&self) -> Option<KernelHints> {
+        Some(self.hints)
+    }
+}
+
+#[test]
+fn test_bitmap_crash_dump() {
+    // Pages 0, 1 and 3 present: two runs, the second one right after the first in the file
+    let mut dmp = vec![0u8; 0x3000];
+    dmp[0..4].copy_from_slice(&DUMP_SIGNATURE);
+    dmp[4..8].copy_from_slice(&DUMP_VALID_DUMP64);
+    LittleEndian::write_u64(&mut dmp[OFFSET_DIRECTORY_TABLE_BASE..], 0x1aa000);
+    LittleEndian::write_u64(&mut dmp[OFFSET_PS_LOADED_MODULE_LIST..], 0xfffff80000001000);
+    LittleEndian::write_u32(&mut dmp[OFFSET_DUMP_TYPE..], DUMP_TYPE_BITMAP_FULL);
+    let summary = DUMP_HEADER64_SIZE as usize;
+    dmp[summary..summary + 4].copy_from_slice(b"FDMP");
+    LittleEndian::write_u64(&mut dmp[summary + OFFSET_SUMMARY_HEADER_SIZE..], 0x3000);
+    LittleEndian::write_u64(&mut dmp[summary + OFFSET_SUMMARY_BITMAP_SIZE..], 4);
+    dmp[summary + OFFSET_SUMMARY_BITMAP] = 0b1011;
+    for page in 0..3u8 {
+        dmp.resize(dmp.len() + PAGE_SIZE as usize, page + 1);
+    }
+
+    let path =
+        std::env::temp_dir().join(format!("libvirtdma-crashdump-{}.dmp", std::process::id()));
+    std::fs::write(&path, &dmp).unwrap();
+    let dump = CrashDump::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let hints = dump.kernel_hints().unwrap();
+    assert_eq!(hints.dtb, 0x1aa000);
+    assert_eq!(hints.ps_loaded_module_list, 0xfffff80000001000);
+    assert_eq!(dump.regions().regions().len(), 2);
+    assert_eq!(dump.holes(), vec![0x2000..0x3000]);
+
+    let mut buf = [0u8; 2];
+    assert!(dump.phys_read(0xfff, &mut buf));
+    assert_eq!(buf, [1, 2]);
+    assert!(dump.phys_read(0x3000, &mut buf));
+    assert_eq!(buf, [3, 3]);
+    assert!(!dump.phys_read(0x2000, &mut buf));
+
+    // Header values that overflow are rejected rather than wrapped
+    LittleEndian::write_u64(&mut dmp[summary + OFFSET_SUMMARY_BITMAP_SIZE..], u64::MAX);
+    assert!(CrashDump::parse_runs(&dmp).is_err());
+    LittleEndian::write_u64(&mut dmp[summary + OFFSET_SUMMARY_BITMAP_SIZE..], 4);
+    LittleEndian::write_u64(&mut dmp[summary + OFFSET_SUMMARY_HEADER_SIZE..], u64::MAX);
+    assert!(CrashDump::parse_runs(&dmp).is_err());
+
+    LittleEndian::write_u32(&mut dmp[OFFSET_DUMP_TYPE..], DUMP_TYPE_FULL);
+    LittleEndian::write_u32(&mut dmp[OFFSET_NUMBER_OF_RUNS..], 1);
+    LittleEndian::write_u64(&mut dmp[OFFSET_RUNS..], 1 << 60);
+    LittleEndian::write_u64(&mut dmp[OFFSET_RUNS + 8..], 1);
+    assert!(CrashDump::parse_runs(&dmp).is_err());
+    LittleEndian::write_u64(&mut dmp[OFFSET_RUNS..], 0);
+    assert_eq!(
+        CrashDump::parse_runs(&dmp).unwrap(),
+        vec![(0, 0x2000, 0x1000)]
+    );
+    // The runs array ends with the memory descriptor, not at the dump type
+    LittleEndian::write_u32(&mut dmp[OFFSET_NUMBER_OF_RUNS..], 44);
+    assert!(CrashDump::parse_runs(&dmp).is_err());
+}
@@ -0,0 +1,215 @@
+use crate::backend::regions::{PhysRegion, RegionMap};
+use crate::backend::PhysicalMemory;
+use crate::error::{self, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{Mmap, MmapOptions};
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+// e_phnum value signalling that the real count lives in sh_info of section header 0
+const PN_XNUM: u16 = 0xffff;
+
+/// ELF core produced by QEMU's `dump-guest-memory` / `virsh dump --memory-only`
+/// or VirtualBox's `dumpvmcore`. Every PT_LOAD segment carries its guest-physical
+/// address in `p_paddr`, so RAM split around the PCI hole is mapped exactly.
+pub struct ElfCore {
+    map: Mmap,
+    regions: RegionMap,
+}
+
+impl ElfCore {
+    pub fn open<P: AsRef<Path>>(path: P) -> error::Result<ElfCore> {
+        let file = File::open(path.as_ref()).map_err(|e| Error::io(&path, e))?;
+        let map = unsafe { MmapOptions::new().map(&file) }.map_err(|e| Error::io(&path, e))?;
+        let regions = Self::parse_load_segments(&map).map_err(|e| {
+            Error::Image(format!(
+                "Unable to parse ELF core {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+        let base = map.as_ptr() as u64;
+        let regions = RegionMap::new(
+            regions
+                .iter()
+                .map(|(paddr, offset, filesz)| PhysRegion {
+                    start: *paddr,
+                    size: *filesz,
+                    host: base + *offset,
+                    mapping: 0,
+                })
+                .collect(),
+        );
+        Ok(ElfCore { map, regions })
+    }
+
+    pub fn regions(&self) -> &RegionMap {
+        &self.regions
+    }
+
+    /// Returns (p_paddr, p_offset, p_filesz) for every PT_LOAD segment.
+    fn parse_load_segments(data: &[u8]) -> Result<Vec<(u64, u64, u64)>, String> {
+        if data.len() < 0x34 || data[0..4] != ELF_MAGIC {
+            return Err("missing ELF magic".to_string());
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err("only little-endian ELF cores are supported".to_string());
+        }
+        let is64 = match data[4] {
+            ELFCLASS64 => true,
+            ELFCLASS32 => false,
+            c => return Err(format!("unknown ELF class {}", c)),
+        };
+        if is64 && data.len() < 0x40 {
+            return Err("truncated ELF header".to_string());
+        }
+        if LittleEndian::read_u16(&data[0x10..]) != ET_CORE {
+            return Err("ELF file is not a core dump".to_string());
+        }
+
+        let (phoff, phentsize, mut phnum, shoff) = if is64 {
+            (
+                LittleEndian::read_u64(&data[0x20..]),
+                LittleEndian::read_u16(&data[0x36..]) as u64,
+                LittleEndian::read_u16(&data[0x38..]) as u64,
+                LittleEndian::read_u64(&data[0x28..]),
+            )
+        } else {
+            (
+                LittleEndian::read_u32(&data[0x1c..]) as u64,
+                LittleEndian::read_u16(&data[0x2a..]) as u64,
+                LittleEndian::read_u16(&data[0x2c..]) as u64,
+                LittleEndian::read_u32(&data[0x20..]) as u64,
+            )
+        };
+        if phnum == PN_XNUM as u64 {
+            let sh_info = shoff
+                .checked_add(if is64 { 44 } else { 28 })
+                .and_then(|begin| data.get(begin as usize..begin.checked_add(4)? as usize));
+            match sh_info {
+                Some(b) => phnum = LittleEndian::read_u32(b) as u64,
+                None => return Err("truncated section header 0".to_string()),
+            }
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let phdr = i
+                .checked_mul(phentsize)
+                .and_then(|o| o.checked_add(phoff))
+                .and_then(|begin| data.get(begin as usize..begin.checked_add(phentsize)? as usize));
+            let phdr = match phdr {
+                Some(p) if p.len() >= if is64 { 0x38 } else { 0x20 } => p,
+                _ => return Err(format!("program header {} is out of bounds", i)),
+            };
+            if LittleEndian::read_u32(phdr) != PT_LOAD {
+                continue;
+            }
+            let (offset, paddr, filesz) = if is64 {
+                (
+                    LittleEndian::read_u64(&phdr[0x8..]),
+                    LittleEndian::read_u64(&phdr[0x18..]),
+                    LittleEndian::read_u64(&phdr[0x20..]),
+                )
+            } else {
+                (
+                    LittleEndian::read_u32(&phdr[0x4..]) as u64,
+                    LittleEndian::read_u32(&phdr[0xc..]) as u64,
+                    LittleEndian::read_u32(&phdr[0x10..]) as u64,
+                )
+            };
+            if offset.saturating_add(filesz) > data.len() as u64 {
+                return Err(format!(
+                    "PT_LOAD for 0x{:x} points past the end of the file",
+                    paddr
+                ));
+            }
+            segments.push((paddr, offset, filesz));
+        }
+        if segments.is_empty() {
+            return Err("no PT_LOAD segments".to_string());
+        }
+        Ok(segments)
+    }
+}
+
+impl PhysicalMemory for ElfCore {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let base = self.map.as_ptr() as u64;
+        let map = &self.map;
+        self.regions.walk(addr, buf.len(), |host, offset, len| {
+            let file_offset = (host - base) as usize;
+            buf[offset..offset + len].copy_from_slice(&map[file_offset..file_offset + len]);
+        })
+    }
+
+    fn phys_write(&self, _addr: u64, _buf: &[u8]) -> bool {
+        false
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn size(&self) -> u64 {
+        self.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.regions.holes()
+    }
+}
+
+#[test]
+fn test_elf_core_split_ram() {
+    // Two adjacent PT_LOADs below 4GiB and one above it, like QEMU's split RAM
+    let segments = [(0u64, 0x1000u64), (0x1000, 0x1000), (0x100000000, 0x1000)];
+    let data_begin = 0x40 + segments.len() * 0x38;
+    let mut elf = vec![0u8; data_begin];
+    elf[0..4].copy_from_slice(&ELF_MAGIC);
+    elf[4] = ELFCLASS64;
+    elf[5] = ELFDATA2LSB;
+    LittleEndian::write_u16(&mut elf[0x10..], ET_CORE);
+    LittleEndian::write_u64(&mut elf[0x20..], 0x40);
+    LittleEndian::write_u16(&mut elf[0x36..], 0x38);
+    LittleEndian::write_u16(&mut elf[0x38..], segments.len() as u16);
+    for (i, (paddr, size)) in segments.iter().enumerate() {
+        let phdr = &mut elf[0x40 + i * 0x38..];
+        LittleEndian::write_u32(phdr, PT_LOAD);
+        LittleEndian::write_u64(&mut phdr[0x8..], (data_begin + i * 0x1000) as u64);
+        LittleEndian::write_u64(&mut phdr[0x18..], *paddr);
+        LittleEndian::write_u64(&mut phdr[0x20..], *size);
+        LittleEndian::write_u64(&mut phdr[0x28..], *size);
+    }
+    elf.extend((0..0x3000u32).map(|i| (i / 0x1000) as u8 + 1));
+
+    let path = std::env::temp_dir().join(format!("libvirtdma-elfcore-{}.core", std::process::id()));
+    std::fs::write(&path, &elf).unwrap();
+    let core = ElfCore::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(core.size(), 0x100001000);
+    assert_eq!(core.holes(), vec![0x2000..0x100000000]);
+
+    let mut buf = [0u8; 2];
+    assert!(core.phys_read(0xfff, &mut buf));
+    assert_eq!(buf, [1, 2]);
+    assert!(core.phys_read(0x100000000, &mut buf));
+    assert_eq!(buf, [3, 3]);
+    // Reads that straddle a hole must fail instead of returning partial data
+    assert!(!core.phys_read(0x1fff, &mut buf));
+
+    // Header offsets that overflow are rejected rather than wrapped
+    LittleEndian::write_u64(&mut elf[0x20..], u64::MAX - 0x10);
+    assert!(ElfCore::parse_load_segments(&elf).is_err());
+    LittleEndian::write_u16(&mut elf[0x38..], PN_XNUM);
+    LittleEndian::write_u64(&mut elf[0x28..], u64::MAX - 0x10);
+    assert!(ElfCore::parse_load_segments(&elf).is_err());
+}
@@ -0,0 +1,131 @@
+use crate::backend::qemu::QemuCmdline;
+use crate::error::{Error, Result};
+
+/// A process holding `/dev/kvm` open, normally one qemu instance per guest.
+#[derive(Debug, Clone)]
+pub struct KvmGuest {
+    pub pid: u64,
+    /// `-name`
+    pub name: Option<String>,
+    /// `-uuid`
+    pub uuid: Option<String>,
+    /// `-m`, if the command line was readable
+    pub ram_size: Option<u64>,
+}
+
+/// Picks one guest out of `list_kvm_guests()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmSelector {
+    Pid(u64),
+    Name(String),
+    Uuid(String),
+}
+
+impl VmSelector {
+    /// A number selects by pid, a UUID by `-uuid`, anything else by `-name`.
+    pub fn parse(s: &str) -> VmSelector {
+        if let Ok(pid) = s.parse::<u64>() {
+            return VmSelector::Pid(pid);
+        }
+        let is_uuid = s.len() == 36
+            && s.chars().enumerate().all(|(i, c)| match i {
+                8 | 13 | 18 | 23 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            });
+        if is_uuid {
+            VmSelector::Uuid(s.to_lowercase())
+        } else {
+            VmSelector::Name(s.to_string())
+        }
+    }
+
+    pub fn matches(&self, guest: &KvmGuest) -> bool {
+        match self {
+            VmSelector::Pid(pid) => guest.pid == *pid,
+            VmSelector::Name(name) => guest.name.as_ref() == Some(name),
+            VmSelector::Uuid(uuid) => {
+                guest.uuid.as_ref().map(|u| u.to_lowercase()) == Some(uuid.clone())
+            }
+        }
+    }
+}
+
+fn uses_kvm(pid: u64) -> bool {
+    let fds = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
+    fds.filter_map(|fd| fd.ok())
+        .any(|fd| match std::fs::read_link(fd.path()) {
+            Ok(target) => target.as_os_str() == "/dev/kvm",
+            Err(_) => false,
+        })
+}
+
+/// Lists the processes using `/dev/kvm` by scanning `/proc/*/fd`, sorted by pid.
+/// Processes whose fds we may not inspect are silently left out.
+pub fn list_kvm_guests() -> Result<Vec<KvmGuest>> {
+    let entries = std::fs::read_dir("/proc").map_err(|e| Error::io("/proc", e))?;
+    let mut guests: Vec<KvmGuest> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u64>().ok()))
+        .filter(|pid| uses_kvm(*pid))
+        .map(|pid| {
+            let cmd = match std::fs::read(format!("/proc/{}/cmdline", pid)) {
+                Ok(cmdline) => Some(QemuCmdline::parse(&cmdline)),
+                Err(_) => None,
+            };
+            KvmGuest {
+                pid,
+                name: cmd.as_ref().and_then(|c| c.name.clone()),
+                uuid: cmd.as_ref().and_then(|c| c.uuid.clone()),
+                ram_size: cmd.as_ref().and_then(|c| c.ram_size),
+            }
+        })
+        .collect();
+    guests.sort_by_key(|g| g.pid);
+    Ok(guests)
+}
+
+/// Finds the guest to attach to. Without a selector the one with the lowest pid is
+/// taken, with a note if there are others to choose from.
+pub fn select_kvm_guest(selector: Option<&VmSelector>) -> Result<KvmGuest> {
+    let guests = list_kvm_guests()?;
+    match selector {
+        Some(sel) => match guests.iter().find(|g| sel.matches(g)) {
+            Some(g) => Ok(g.clone()),
+            None => Err(Error::NoGuest(format!("No running VM matches {:?}", sel))),
+        },
+        None => {
+            if guests.len() > 1 {
+                warn!(
+                    "{} VMs are running, attaching to pid {} (pick another with a pid, name or uuid)",
+                    guests.len(),
+                    guests[0].pid
+                );
+            }
+            match guests.first() {
+                Some(g) => Ok(g.clone()),
+                None => Err(Error::NoGuest("No process is using /dev/kvm".to_string())),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_vm_selector() {
+    let guest = KvmGuest {
+        pid: 4242,
+        name: Some("win10".to_string()),
+        uuid: Some("5B1E4C2A-9F3D-4E2B-8A7C-1D2E3F405162".to_string()),
+        ram_size: Some(8 << 30),
+    };
+    assert!(VmSelector::parse("4242").matches(&guest));
+    assert!(VmSelector::parse("win10").matches(&guest));
+    assert!(VmSelector::parse("5b1e4c2a-9f3d-4e2b-8a7c-1d2e3f405162").matches(&guest));
+    assert!(!VmSelector::parse("win7").matches(&guest));
+    assert_eq!(
+        VmSelector::parse("not-a-uuid-but-36-characters-long-xx"),
+        VmSelector::Name("not-a-uuid-but-36-characters-long-xx".to_string())
+    );
+}
@@ -0,0 +1,435 @@
+use crate::backend::guests::{select_kvm_guest, VmSelector};
+use crate::backend::qemu::{MachineType, QemuCmdline};
+use crate::backend::qmp::{ram_regions, QmpClient};
+use crate::backend::regions::RegionMap;
+use crate::backend::PhysicalMemory;
+use crate::error::{Error, Result};
+use crate::vm::vmread_bind;
+use crate::vm::ProcessData;
+use nix::fcntl::open;
+use nix::unistd::close;
+use proc_maps::{MapRange, Pid};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// How far below qemu's address the kernel module maps guest RAM into ours (0x20 PML4 slots).
+const KMOD_TARGET_DELTA: u64 = 0x20 << 39;
+
+/// Guest RAM of a qemu-kvm process: the host mappings holding it, and the
+/// guest-physical region table whose `host` addresses point into those mappings.
+#[derive(Debug, Clone)]
+pub struct GuestRam {
+    pub pid: i32,
+    pub mappings: Vec<ProcessData>,
+    pub regions: RegionMap,
+    /// QMP socket of the qemu process, if its command line names one
+    pub monitor: Option<PathBuf>,
+}
+
+/// What locating RAM blocks needs to know about a host mapping of the qemu process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostMapping {
+    pub start: u64,
+    pub size: u64,
+    /// Readable and writable
+    pub rw: bool,
+    /// `MAP_SHARED`, like a `memory-backend-file` or `memory-backend-memfd`
+    pub shared: bool,
+    pub path: Option<String>,
+}
+
+impl From<&MapRange> for HostMapping {
+    fn from(m: &MapRange) -> HostMapping {
+        HostMapping {
+            start: m.start() as u64,
+            size: m.size() as u64,
+            rw: m.is_read() && m.is_write(),
+            shared: m.flags.get(3..4) == Some("s"),
+            path: m.filename().clone(),
+        }
+    }
+}
+
+impl GuestRam {
+    /// Points every region inside `mappings[idx]` at the same offset in `to`,
+    /// for when the mapping has been made reachable at another address.
+    pub fn remap(&mut self, idx: usize, to: ProcessData) {
+        let from = self.mappings[idx];
+        self.regions.rebase(idx, from.maps_start, to.maps_start);
+        self.mappings[idx] = to;
+    }
+}
+
+/// Guest RAM of the qemu-kvm process, mapped into our address space by the
+/// `/proc/vmread` kernel module. This is the fastest live backend.
+pub struct KvmMemory {
+    pub ram: GuestRam,
+}
+
+impl KvmMemory {
+    pub fn new() -> Result<KvmMemory> {
+        Self::from_guest_ram(Self::create_guest_ram(None)?)
+    }
+
+    /// Asks the kernel module to map every host mapping of `ram` into our address space.
+    pub fn from_guest_ram(ram: GuestRam) -> Result<KvmMemory> {
+        let mut mem = KvmMemory { ram };
+        mem.init_device()?;
+        Ok(mem)
+    }
+
+    /// Locates the qemu-kvm process picked by `vm` and lays its guest RAM out in guest-physical space.
+    /// qemu is asked over QMP when the command line names a QMP socket; otherwise the
+    /// layout is derived from the machine type, RAM size, NUMA nodes and DIMMs on the
+    /// command line. Without either the largest mapping is assumed to be all of RAM.
+    pub fn create_guest_ram(vm: Option<&VmSelector>) -> Result<GuestRam> {
+        let pid = select_kvm_guest(vm)?.pid;
+        let mut pmaps: Vec<HostMapping> = proc_maps::get_process_maps(pid as Pid)
+            .map_err(|e| Error::io(format!("/proc/{}/maps", pid), e))?
+            .iter()
+            .map(HostMapping::from)
+            .collect();
+        pmaps.sort_by_key(|m| m.start);
+        let cmdline = QemuCmdline::read(pid);
+        if let Ok(cmd) = &cmdline {
+            for socket in cmd.qmp_sockets.iter() {
+                let ram = QmpClient::connect(socket).and_then(|mut qmp| {
+                    Self::guest_ram_from_qmp(pid, &mut pmaps.clone(), &mut qmp)
+                });
+                match ram {
+                    Ok(mut ram) => {
+                        ram.monitor = Some(PathBuf::from(socket));
+                        return Ok(ram);
+                    }
+                    Err(e) => warn!(
+                        "Unable to get the memory layout over QMP socket {}: {}",
+                        socket, e
+                    ),
+                }
+            }
+        }
+        let cmd = match cmdline {
+            Ok(c) if c.ram_size.is_some() => c,
+            _ => {
+                warn!(
+                    "No -m on the qemu-kvm command line, assuming the largest mapping is guest RAM"
+                );
+                let mut cmd = QemuCmdline::parse(b"");
+                cmd.machine = MachineType::Q35;
+                cmd.ram_size = pmaps.iter().map(|m| m.size).max();
+                cmd
+            }
+        };
+        let mut mappings = Vec::new();
+        let mut boot = Vec::new();
+        for size in cmd.boot_blocks() {
+            match Self::take_mapping(&mut pmaps, size) {
+                Some(m) => {
+                    boot.push((m.start, size, mappings.len()));
+                    mappings.push(ProcessData {
+                        maps_start: m.start,
+                        maps_size: size,
+                        pid: pid as i32,
+                    });
+                }
+                None => {
+                    return Err(Error::Backend(format!(
+                        "Unable to find the 0x{:x} byte guest RAM block in qemu-kvm",
+                        size
+                    )));
+                }
+            }
+        }
+        let mut dimms = Vec::new();
+        for size in cmd.dimm_blocks() {
+            if size == 0 {
+                dimms.push((0, 0, 0));
+                continue;
+            }
+            match Self::take_mapping(&mut pmaps, size) {
+                Some(m) => {
+                    dimms.push((m.start, size, mappings.len()));
+                    mappings.push(ProcessData {
+                        maps_start: m.start,
+                        maps_size: size,
+                        pid: pid as i32,
+                    });
+                }
+                None => {
+                    warn!(
+                        "Unable to find the 0x{:x} byte DIMM block in qemu-kvm, skipping it",
+                        size
+                    );
+                    dimms.push((0, 0, 0));
+                }
+            }
+        }
+        Ok(GuestRam {
+            pid: pid as i32,
+            mappings,
+            regions: RegionMap::new(cmd.guest_regions(&boot, &dimms)),
+            monitor: cmd.qmp_sockets.first().map(PathBuf::from),
+        })
+    }
+
+    /// Builds the region table from qemu's flattened view of guest-physical memory,
+    /// locating each RAM block it references among the mappings in `pmaps`. Fails
+    /// unless every block is found, leaving the caller to fall back to the command line.
+    pub fn guest_ram_from_qmp(
+        pid: u64,
+        pmaps: &mut Vec<HostMapping>,
+        qmp: &mut QmpClient,
+    ) -> Result<GuestRam> {
+        let ram_mappings = qmp.ram_mappings()?;
+        if ram_mappings.is_empty() {
+            return Err(Error::Qmp("no guest RAM in the memory tree".to_string()));
+        }
+        let devices = qmp.query_memory_devices()?;
+        let mut blocks = QmpClient::ram_blocks(&ram_mappings, &devices);
+        // Big blocks first, so small ones can't steal their mappings
+        blocks.sort_by_key(|b| std::cmp::Reverse(b.1));
+        let mut hosts = HashMap::new();
+        let mut mappings = Vec::new();
+        for (name, size) in blocks {
+            match Self::take_mapping(pmaps, size) {
+                Some(m) => {
+                    hosts.insert(name, (m.start, mappings.len()));
+                    mappings.push(ProcessData {
+                        maps_start: m.start,
+                        maps_size: size,
+                        pid: pid as i32,
+                    });
+                }
+                None => {
+                    return Err(Error::Backend(format!(
+                        "Unable to find RAM block {} (0x{:x} bytes) in qemu-kvm",
+                        name, size
+                    )));
+                }
+            }
+        }
+        if mappings.is_empty() {
+            return Err(Error::Backend(
+                "no RAM block in the memory tree of qemu-kvm".to_string(),
+            ));
+        }
+        Ok(GuestRam {
+            pid: pid as i32,
+            mappings,
+            regions: RegionMap::new(ram_regions(&ram_mappings, &hosts)),
+            monitor: None,
+        })
+    }
+
+    /// Removes and returns the mapping holding a RAM block of `size` bytes: the first
+    /// read-write exact match, otherwise the smallest anonymous or shared mapping that
+    /// blocks of this size could have been merged into. Any other mapping (the heap, a
+    /// library) would have guest reads return host memory, so there is none.
+    fn take_mapping(pmaps: &mut Vec<HostMapping>, size: u64) -> Option<HostMapping> {
+        if size == 0 {
+            return None;
+        }
+        let idx = match pmaps.iter().position(|m| {
+            m.rw && m.size == size && !matches!(&m.path, Some(p) if p.starts_with('['))
+        }) {
+            Some(i) => i,
+            None => {
+                pmaps
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| {
+                        m.rw && (m.path.is_none() || m.shared)
+                            && m.size > size
+                            && m.size.is_multiple_of(size)
+                    })
+                    .min_by_key(|(_, m)| m.size)?
+                    .0
+            }
+        };
+        Some(pmaps.remove(idx))
+    }
+
+    /// The module maps qemu's range 16TiB lower in our address space, without checking
+    /// what is already there; two guests with nearby qemu addresses would collide.
+    fn kmod_target_in_use(process: &ProcessData) -> Option<Range<u64>> {
+        let start = match process.maps_start.checked_sub(KMOD_TARGET_DELTA) {
+            Some(s) => s,
+            None => return Some(0..process.maps_size),
+        };
+        let end = start + process.maps_size;
+        let ours = match proc_maps::get_process_maps(std::process::id() as Pid) {
+            Ok(pm) => pm,
+            Err(_) => return None,
+        };
+        ours.iter()
+            .map(|m| m.start() as u64..(m.start() + m.size()) as u64)
+            .find(|m| m.start < end && start < m.end)
+    }
+
+    fn init_device(&mut self) -> Result<()> {
+        let fd: i32 = match open(
+            "/proc/vmread",
+            nix::fcntl::OFlag::O_RDWR,
+            nix::sys::stat::Mode::S_IRWXO,
+        ) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(Error::Backend(format!(
+                    "Failed to open /proc/vmread: {}",
+                    e
+                )));
+            }
+        };
+        let mut res = Ok(());
+        for idx in 0..self.ram.mappings.len() {
+            let mut process = self.ram.mappings[idx];
+            if let Some(existing) = Self::kmod_target_in_use(&process) {
+                res = Err(Error::Backend(format!(
+                    "vmread kernel module would map guest RAM over 0x{:x}-0x{:x}, already in use (another binding?)",
+                    existing.start, existing.end
+                )));
+                break;
+            }
+            match unsafe { vmread_bind(fd, &mut process) } {
+                // The module reports a successful mapping by handing back our own pid
+                Ok(0) if process.pid == std::process::id() as i32 => self.ram.remap(idx, process),
+                Ok(_) => {
+                    res = Err(Error::Backend(
+                        "vmread kernel module failed to map the guest memory".to_string(),
+                    ));
+                    break;
+                }
+                Err(e) => {
+                    res = Err(Error::Backend(format!(
+                        "Failed to call vmread ioctl: {}",
+                        e
+                    )));
+                    break;
+                }
+            };
+        }
+        if let Err(e) = close(fd) {
+            warn!(
+                "Error while closing the file descriptor to /proc/vmread: {}",
+                e
+            );
+        }
+        res
+    }
+}
+
+impl Drop for KvmMemory {
+    fn drop(&mut self) {
+        // Only the mappings the module placed in our address space, not qemu's originals
+        let ours = std::process::id() as i32;
+        for m in self.ram.mappings.iter().filter(|m| m.pid == ours) {
+            unsafe {
+                libc::munmap(m.maps_start as *mut libc::c_void, m.maps_size as usize);
+            }
+        }
+    }
+}
+
+impl PhysicalMemory for KvmMemory {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        self.ram
+            .regions
+            .walk(addr, buf.len(), |host, offset, len| unsafe {
+                libc::memcpy(
+                    buf[offset..].as_mut_ptr() as *mut libc::c_void,
+                    host as *mut libc::c_void,
+                    len as libc::size_t,
+                );
+            })
+    }
+
+    fn phys_write(&self, addr: u64, buf: &[u8]) -> bool {
+        self.ram
+            .regions
+            .walk(addr, buf.len(), |host, offset, len| unsafe {
+                libc::memcpy(
+                    host as *mut libc::c_void,
+                    buf[offset..].as_ptr() as *mut libc::c_void,
+                    len as libc::size_t,
+                );
+            })
+    }
+
+    fn size(&self) -> u64 {
+        self.ram.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.ram.regions.holes()
+    }
+
+    fn monitor_socket(&self) -> Option<PathBuf> {
+        self.ram.monitor.clone()
+    }
+}
+
+#[test]
+fn test_take_mapping() {
+    let map = |start: u64, size: u64, shared: bool, path: Option<&str>| HostMapping {
+        start,
+        size,
+        rw: true,
+        shared,
+        path: path.map(String::from),
+    };
+    let mut pmaps = vec![
+        map(0x1000, 0x3000_0000, false, Some("[heap]")),
+        map(0x4000_0000, 0x3800_0000, false, Some("/usr/lib/libfoo.so")),
+        map(0x8000_0000, 0x4000_0000, false, None),
+        map(0xc000_0000, 0x1000_0000, true, Some("/memfd:ram (deleted)")),
+    ];
+    assert_eq!(
+        KvmMemory::take_mapping(&mut pmaps, 0x1000_0000)
+            .unwrap()
+            .start,
+        0xc000_0000
+    );
+    // Merged into the anonymous mapping, never the heap or the library
+    assert_eq!(
+        KvmMemory::take_mapping(&mut pmaps, 0x2000_0000)
+            .unwrap()
+            .start,
+        0x8000_0000
+    );
+    assert!(KvmMemory::take_mapping(&mut pmaps, 0x1000_0000).is_none());
+    assert!(KvmMemory::take_mapping(&mut pmaps, 0x3000_0000).is_none());
+    assert_eq!(pmaps.len(), 2);
+}
+
+#[test]
+fn test_guest_ram_remap() {
+    use crate::backend::regions::PhysRegion;
+
+    let mapping = |maps_start: u64, maps_size: u64| ProcessData {
+        maps_start,
+        maps_size,
+        pid: 1,
+    };
+    let region = |start: u64, host: u64, mapping: usize| PhysRegion {
+        start,
+        size: 0x1000,
+        host,
+        mapping,
+    };
+    let mut ram = GuestRam {
+        pid: 1,
+        mappings: vec![mapping(0x10000, 0x2000), mapping(0x20000, 0x1000)],
+        regions: RegionMap::new(vec![
+            region(0, 0x10000, 0),
+            region(0x1000, 0x11000, 0),
+            region(0x2000, 0x20000, 1),
+        ]),
+        monitor: None,
+    };
+    // The first mapping lands inside where qemu has the second, which must not move it again
+    ram.remap(0, mapping(0x20000, 0x2000));
+    ram.remap(1, mapping(0x30000, 0x1000));
+    let hosts: Vec<u64> = ram.regions.regions().iter().map(|r| r.host).collect();
+    assert_eq!(hosts, vec![0x20000, 0x21000, 0x30000]);
+}
@@ -0,0 +1,165 @@
+use crate::backend::crashdump::{CrashDump, DUMP_SIGNATURE};
+use crate::backend::elfcore::{ElfCore, ELF_MAGIC};
+use crate::backend::guests::VmSelector;
+use crate::backend::kvm::{GuestRam, KvmMemory};
+use crate::backend::procmem::{ProcMemFile, ProcessVmMemory};
+use crate::backend::raw::RawImage;
+use crate::backend::shared::SharedRamMemory;
+use crate::backend::vmware::{is_vmss_magic, VmwareSnapshot};
+use crate::error::Result;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub mod crashdump;
+pub mod elfcore;
+pub mod guests;
+pub mod kvm;
+pub mod procmem;
+pub mod qemu;
+pub mod qmp;
+pub mod raw;
+pub mod regions;
+pub mod shared;
+pub mod vmware;
+
+/// How a live backend reaches the guest RAM inside the qemu-kvm process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LiveAccess {
+    /// Mapped into our address space by the `/proc/vmread` kernel module
+    KernelModule,
+    /// qemu's shared `memory-backend-file`/`memory-backend-memfd`, mapped directly
+    SharedFile,
+    /// `process_vm_readv`/`process_vm_writev`
+    ProcessVm,
+    /// `pread`/`pwrite` on `/proc/<pid>/mem`
+    ProcMem,
+}
+
+/// Kernel locations some formats record up front, letting the binding skip the
+/// low stub and ntoskrnl scans.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelHints {
+    pub dtb: u64,
+    pub ps_loaded_module_list: u64,
+    pub ps_active_process_head: u64,
+}
+
+/// Source of guest-physical memory for a `VMBinding`.
+///
+/// Everything above this trait (address translation, EPROCESS walking,
+/// PE parsing) only ever deals with guest-physical addresses, so any
+/// implementation that can serve those -- a live VM, a dump file, a mock --
+/// can back a binding.
+pub trait PhysicalMemory: Send + Sync {
+    /// Fills `buf` with the guest-physical memory starting at `addr`.
+    /// Returns false if any part of the range is not backed.
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool;
+
+    /// Writes `buf` to the guest-physical memory starting at `addr`.
+    /// Returns false if any part of the range is not backed or the backend is read-only.
+    fn phys_write(&self, addr: u64, buf: &[u8]) -> bool;
+
+    /// One past the highest guest-physical address this backend can serve.
+    fn size(&self) -> u64;
+
+    /// Guest-physical ranges below `size()` that have no backing.
+    fn holes(&self) -> Vec<Range<u64>> {
+        Vec::new()
+    }
+
+    /// Kernel locations known without scanning guest memory, if the backend has them.
+    fn kernel_hints(&self) -> Option<KernelHints> {
+        None
+    }
+
+    /// QMP socket of the VM behind a live backend, used to pause and resume it.
+    fn monitor_socket(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether the guest may change the memory behind our back. False for images, whose
+    /// translations can be cached for good.
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+/// Opens a memory image file, picking the backend from its leading magic bytes
+/// and falling back to a flat raw image.
+pub fn open_image<P: AsRef<Path>>(path: P) -> Result<Box<dyn PhysicalMemory>> {
+    let mut magic = [0u8; 4];
+    if let Ok(mut f) = std::fs::File::open(path.as_ref()) {
+        if f.read_exact(&mut magic).is_err() {
+            magic = [0u8; 4];
+        }
+    }
+    let is_vmem = match path.as_ref().extension() {
+        Some(ext) => ext.eq_ignore_ascii_case("vmem"),
+        None => false,
+    };
+    // A .vmem without a state file next to it is a flat image; one that has a state file
+    // but no region table is mapped flat by `VmwareSnapshot` itself
+    if is_vmem && VmwareSnapshot::state_file(path.as_ref()).is_none() {
+        info!(
+            "No VMware state file next to {}, treating it as a flat memory image",
+            path.as_ref().display()
+        );
+    } else if is_vmss_magic(&magic) || is_vmem {
+        return Ok(Box::new(VmwareSnapshot::open_pair(path)?));
+    }
+    if magic == DUMP_SIGNATURE {
+        Ok(Box::new(CrashDump::open(path)?))
+    } else if magic == ELF_MAGIC {
+        Ok(Box::new(ElfCore::open(path)?))
+    } else {
+        Ok(Box::new(RawImage::open(path)?))
+    }
+}
+
+/// Opens the guest RAM of the running qemu-kvm process picked by `vm` (the only one if
+/// None). With no explicit `access` the kernel module is tried first, then a shared RAM
+/// backing file, then `process_vm_readv`, then `/proc/<pid>/mem`.
+pub fn open_live(
+    vm: Option<&VmSelector>,
+    access: Option<LiveAccess>,
+) -> Result<Box<dyn PhysicalMemory>> {
+    open_guest_ram(KvmMemory::create_guest_ram(vm)?, access)
+}
+
+/// Like `open_live`, for guest RAM that has already been located (or described by hand).
+pub fn open_guest_ram(
+    ram: GuestRam,
+    access: Option<LiveAccess>,
+) -> Result<Box<dyn PhysicalMemory>> {
+    match access {
+        Some(LiveAccess::KernelModule) => Ok(Box::new(KvmMemory::from_guest_ram(ram)?)),
+        Some(LiveAccess::SharedFile) => Ok(Box::new(SharedRamMemory::from_guest_ram(ram)?)),
+        Some(LiveAccess::ProcessVm) => Ok(Box::new(ProcessVmMemory::new(ram)?)),
+        Some(LiveAccess::ProcMem) => Ok(Box::new(ProcMemFile::open(ram)?)),
+        None => {
+            match KvmMemory::from_guest_ram(ram.clone()) {
+                Ok(m) => return Ok(Box::new(m)),
+                Err(e) => info!(
+                    "Kernel module unavailable ({}), trying the shared RAM backing file",
+                    e
+                ),
+            }
+            match SharedRamMemory::from_guest_ram(ram.clone()) {
+                Ok(m) => return Ok(Box::new(m)),
+                Err(e) => info!(
+                    "No shared RAM backing file ({}), trying process_vm_readv",
+                    e
+                ),
+            }
+            match ProcessVmMemory::new(ram.clone()) {
+                Ok(m) => return Ok(Box::new(m)),
+                Err(e) => info!(
+                    "process_vm_readv unavailable ({}), trying /proc/{}/mem",
+                    e, ram.pid
+                ),
+            }
+            Ok(Box::new(ProcMemFile::open(ram)?))
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::backend::kvm::GuestRam;
+use crate::backend::PhysicalMemory;
+use crate::error::{Error, Result};
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// Guest RAM of the qemu-kvm process reached through `process_vm_readv`/`process_vm_writev`.
+/// Needs no kernel module, only ptrace access to the qemu process.
+pub struct ProcessVmMemory {
+    pub ram: GuestRam,
+}
+
+impl ProcessVmMemory {
+    /// Fails if a probe read of the first guest page is refused.
+    pub fn new(ram: GuestRam) -> Result<ProcessVmMemory> {
+        let mem = ProcessVmMemory { ram };
+        let mut probe = [0u8; 8];
+        if !mem.phys_read(0, &mut probe) {
+            return Err(Error::Backend(format!(
+                "process_vm_readv on qemu-kvm (pid {}) failed, check ptrace permissions",
+                mem.ram.pid
+            )));
+        }
+        Ok(mem)
+    }
+}
+
+impl PhysicalMemory for ProcessVmMemory {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let pid = Pid::from_raw(self.ram.pid);
+        let mut ok = true;
+        let covered = self.ram.regions.walk(addr, buf.len(), |host, offset, len| {
+            let remote_iov = [RemoteIoVec {
+                base: host as usize,
+                len,
+            }];
+            let local_iov = [IoVec::from_mut_slice(&mut buf[offset..offset + len])];
+            ok &= match process_vm_readv(pid, &local_iov, &remote_iov) {
+                Ok(read) => read == len,
+                Err(_) => false,
+            };
+        });
+        covered && ok
+    }
+
+    fn phys_write(&self, addr: u64, buf: &[u8]) -> bool {
+        let pid = Pid::from_raw(self.ram.pid);
+        let mut ok = true;
+        let covered = self.ram.regions.walk(addr, buf.len(), |host, offset, len| {
+            let remote_iov = [RemoteIoVec {
+                base: host as usize,
+                len,
+            }];
+            let local_iov = [IoVec::from_slice(&buf[offset..offset + len])];
+            ok &= match process_vm_writev(pid, &local_iov, &remote_iov) {
+                Ok(written) => written == len,
+                Err(_) => false,
+            };
+        });
+        covered && ok
+    }
+
+    fn size(&self) -> u64 {
+        self.ram.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.ram.regions.holes()
+    }
+
+    fn monitor_socket(&self) -> Option<PathBuf> {
+        self.ram.monitor.clone()
+    }
+}
+
+/// Guest RAM of the qemu-kvm process reached through `pread`/`pwrite` on `/proc/<pid>/mem`.
+/// Same privileges as `ProcessVmMemory`, but also works where `process_vm_*` is filtered out.
+pub struct ProcMemFile {
+    pub ram: GuestRam,
+    file: File,
+    writable: bool,
+}
+
+impl ProcMemFile {
+    pub fn open(ram: GuestRam) -> Result<ProcMemFile> {
+        let path = format!("/proc/{}/mem", ram.pid);
+        let (file, writable) = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(f) => (f, true),
+            Err(_) => match File::open(&path) {
+                Ok(f) => {
+                    warn!("Opened {} read-only, writes will fail", path);
+                    (f, false)
+                }
+                Err(e) => return Err(Error::io(path, e)),
+            },
+        };
+        Ok(ProcMemFile {
+            ram,
+            file,
+            writable,
+        })
+    }
+}
+
+impl PhysicalMemory for ProcMemFile {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let mut ok = true;
+        let covered = self.ram.regions.walk(addr, buf.len(), |host, offset, len| {
+            ok &= self
+                .file
+                .read_exact_at(&mut buf[offset..offset + len], host)
+                .is_ok();
+        });
+        covered && ok
+    }
+
+    fn phys_write(&self, addr: u64, buf: &[u8]) -> bool {
+        if !self.writable {
+            return false;
+        }
+        let mut ok = true;
+        let covered = self.ram.regions.walk(addr, buf.len(), |host, offset, len| {
+            ok &= self
+                .file
+                .write_all_at(&buf[offset..offset + len], host)
+                .is_ok();
+        });
+        covered && ok
+    }
+
+    fn size(&self) -> u64 {
+        self.ram.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.ram.regions.holes()
+    }
+
+    fn monitor_socket(&self) -> Option<PathBuf> {
+        self.ram.monitor.clone()
+    }
+}
@@ -0,0 +1,357 @@
+use crate::backend::regions::PhysRegion;
+use crate::error::{Error, Result};
+
+const GIB: u64 = 1 << 30;
+const DIMM_ALIGN: u64 = 2 << 20;
+const FOUR_GIB: u64 = 4 * GIB;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineType {
+    /// `pc`/`pc-i440fx-*`, qemu's default
+    I440fx,
+    /// `q35`/`pc-q35-*`
+    Q35,
+}
+
+/// A `-object memory-backend-*` found on the qemu command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryBackendArg {
+    pub id: Option<String>,
+    pub size: Option<u64>,
+    pub mem_path: Option<String>,
+    pub memfd: bool,
+    pub share: bool,
+}
+
+/// A cold-plugged `-device pc-dimm`/`nvdimm`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DimmArg {
+    pub memdev: String,
+    pub addr: Option<u64>,
+}
+
+/// The parts of a qemu command line that decide where guest RAM ends up.
+#[derive(Debug, Clone)]
+pub struct QemuCmdline {
+    /// `-name`
+    pub name: Option<String>,
+    /// `-uuid`
+    pub uuid: Option<String>,
+    pub machine: MachineType,
+    pub ram_size: Option<u64>,
+    pub max_ram_below_4g: Option<u64>,
+    /// `-machine memory-backend=`
+    pub machine_memdev: Option<String>,
+    /// `-numa node,memdev=` in node order
+    pub numa_memdevs: Vec<String>,
+    pub backends: Vec<MemoryBackendArg>,
+    pub dimms: Vec<DimmArg>,
+    /// `-qmp unix:` sockets. libvirt's own `-mon` monitor is left alone: libvirtd holds
+    /// it, and it can still be passed to `VMBindingBuilder::monitor` by hand.
+    pub qmp_sockets: Vec<String>,
+}
+
+/// Parses a qemu size (`8G`, `512M`, `size=8388608k`); bare numbers are in `default_unit` bytes.
+pub fn parse_size(s: &str, default_unit: u64) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => (
+            &s[..s.len() - 1],
+            match c.to_ascii_uppercase() {
+                'B' => 1,
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return None,
+            },
+        ),
+        _ => (s, default_unit),
+    };
+    match num.parse::<u64>() {
+        Ok(n) => n.checked_mul(unit),
+        Err(_) => None,
+    }
+}
+
+fn props(arg: &str) -> impl Iterator<Item = (&str, &str)> {
+    arg.split(',').map(|prop| {
+        let mut kv = prop.splitn(2, '=');
+        (kv.next().unwrap_or(""), kv.next().unwrap_or(""))
+    })
+}
+
+impl QemuCmdline {
+    /// Reads `/proc/<pid>/cmdline` of a qemu process.
+    pub fn read(pid: u64) -> Result<QemuCmdline> {
+        let path = format!("/proc/{}/cmdline", pid);
+        match std::fs::read(&path) {
+            Ok(cmdline) => Ok(Self::parse(&cmdline)),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// Parses a NUL-separated qemu command line.
+    pub fn parse(cmdline: &[u8]) -> QemuCmdline {
+        let args: Vec<String> = cmdline
+            .split(|b| *b == 0)
+            .map(|a| String::from_utf8_lossy(a).to_string())
+            .collect();
+        let mut cmd = QemuCmdline {
+            name: None,
+            uuid: None,
+            machine: MachineType::I440fx,
+            ram_size: None,
+            max_ram_below_4g: None,
+            machine_memdev: None,
+            numa_memdevs: Vec::new(),
+            backends: Vec::new(),
+            dimms: Vec::new(),
+            qmp_sockets: Vec::new(),
+        };
+        for pair in args.windows(2) {
+            let value = pair[1].as_str();
+            match pair[0].as_str() {
+                "-name" => {
+                    for (k, v) in props(value) {
+                        match (k, v) {
+                            (name, "") | ("guest", name) => cmd.name = Some(name.to_string()),
+                            _ => {}
+                        }
+                    }
+                }
+                "-uuid" => cmd.uuid = Some(value.to_string()),
+                "-m" => {
+                    for (k, v) in props(value) {
+                        match (k, v) {
+                            (size, "") => cmd.ram_size = parse_size(size, 1 << 20),
+                            ("size", v) => cmd.ram_size = parse_size(v, 1 << 20),
+                            _ => {}
+                        }
+                    }
+                }
+                "-machine" | "-M" => {
+                    for (k, v) in props(value) {
+                        match (k, v) {
+                            (ty, "") | ("type", ty) if ty.contains("q35") => {
+                                cmd.machine = MachineType::Q35
+                            }
+                            ("max-ram-below-4g", v) => cmd.max_ram_below_4g = parse_size(v, 1),
+                            ("memory-backend", v) => cmd.machine_memdev = Some(v.to_string()),
+                            _ => {}
+                        }
+                    }
+                }
+                "-numa" if value.starts_with("node") => {
+                    if let Some((_, v)) = props(value).find(|(k, _)| *k == "memdev") {
+                        cmd.numa_memdevs.push(v.to_string());
+                    }
+                }
+                "-object" if value.starts_with("memory-backend-") => {
+                    let memfd = value.starts_with("memory-backend-memfd");
+                    // memfd backends are shared unless told otherwise, file backends are private
+                    let mut backend = MemoryBackendArg {
+                        id: None,
+                        size: None,
+                        mem_path: None,
+                        memfd,
+                        share: memfd,
+                    };
+                    for (k, v) in props(value).skip(1) {
+                        match k {
+                            "id" => backend.id = Some(v.to_string()),
+                            "size" => backend.size = parse_size(v, 1),
+                            "mem-path" => backend.mem_path = Some(v.to_string()),
+                            "share" => backend.share = v == "on" || v == "yes",
+                            _ => {}
+                        }
+                    }
+                    cmd.backends.push(backend);
+                }
+                "-device" if value.starts_with("pc-dimm") || value.starts_with("nvdimm") => {
+                    let mut memdev = None;
+                    let mut addr = None;
+                    for (k, v) in props(value).skip(1) {
+                        match k {
+                            "memdev" => memdev = Some(v.to_string()),
+                            "addr" => addr = parse_u64_any(v),
+                            _ => {}
+                        }
+                    }
+                    if let Some(memdev) = memdev {
+                        cmd.dimms.push(DimmArg { memdev, addr });
+                    }
+                }
+                "-qmp" if value.starts_with("unix:") => {
+                    if let Some((path, _)) = props(&value[5..]).next() {
+                        cmd.qmp_sockets.push(path.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        cmd
+    }
+
+    pub fn backend(&self, id: &str) -> Option<&MemoryBackendArg> {
+        self.backends.iter().find(|b| b.id.as_deref() == Some(id))
+    }
+
+    /// Sizes of the host RAM blocks making up boot memory, in guest order.
+    pub fn boot_blocks(&self) -> Vec<u64> {
+        let memdevs: Vec<&String> = if !self.numa_memdevs.is_empty() {
+            self.numa_memdevs.iter().collect()
+        } else {
+            self.machine_memdev.iter().collect()
+        };
+        let sizes: Option<Vec<u64>> = memdevs
+            .iter()
+            .map(|id| self.backend(id).and_then(|b| b.size))
+            .collect();
+        match sizes {
+            Some(s) if !s.is_empty() => s,
+            _ => self.ram_size.into_iter().collect(),
+        }
+    }
+
+    /// Sizes of the cold-plugged DIMMs' host blocks, in command line order
+    /// (0 where the backend's size isn't on the command line).
+    pub fn dimm_blocks(&self) -> Vec<u64> {
+        self.dimms
+            .iter()
+            .map(|d| self.backend(&d.memdev).and_then(|b| b.size).unwrap_or(0))
+            .collect()
+    }
+
+    /// How much of boot RAM qemu places below 4G for this machine type.
+    pub fn below_4g(&self, ram_size: u64) -> u64 {
+        let mut lowmem = match self.machine {
+            MachineType::Q35 => {
+                if ram_size >= 0xb0000000 {
+                    0x80000000
+                } else {
+                    0xb0000000
+                }
+            }
+            // gigabyte_align on every pc machine type since 1.7
+            MachineType::I440fx => {
+                if ram_size >= 0xe0000000 {
+                    0xc0000000
+                } else {
+                    0xe0000000
+                }
+            }
+        };
+        if let Some(max) = self.max_ram_below_4g {
+            lowmem = std::cmp::min(lowmem, max);
+        }
+        std::cmp::min(lowmem, ram_size)
+    }
+
+    /// Lays the host blocks out in guest-physical space. `boot` and `dimms` hold
+    /// `(host, size, mapping)` per block, in the order of `boot_blocks()`/`dimm_blocks()`.
+    pub fn guest_regions(
+        &self,
+        boot: &[(u64, u64, usize)],
+        dimms: &[(u64, u64, usize)],
+    ) -> Vec<PhysRegion> {
+        let ram_size: u64 = boot.iter().map(|b| b.1).sum();
+        let lowmem = self.below_4g(ram_size);
+        let mut regions = Vec::new();
+        let mut ram_offset = 0u64;
+        for &(host, size, mapping) in boot.iter() {
+            // Boot RAM is one linear space cut at lowmem, the rest continues at 4G
+            let low = if ram_offset < lowmem {
+                std::cmp::min(size, lowmem - ram_offset)
+            } else {
+                0
+            };
+            regions.push(PhysRegion {
+                start: ram_offset,
+                size: low,
+                host,
+                mapping,
+            });
+            regions.push(PhysRegion {
+                start: FOUR_GIB + ram_offset + low - lowmem,
+                size: size - low,
+                host: host + low,
+                mapping,
+            });
+            ram_offset += size;
+        }
+        let mut next_dimm = align_up(FOUR_GIB + (ram_size - lowmem), GIB);
+        for (&(host, size, mapping), dimm) in dimms.iter().zip(self.dimms.iter()) {
+            let start = match dimm.addr {
+                Some(a) => a,
+                None => align_up(next_dimm, DIMM_ALIGN),
+            };
+            regions.push(PhysRegion {
+                start,
+                size,
+                host,
+                mapping,
+            });
+            next_dimm = std::cmp::max(next_dimm, start + size);
+        }
+        regions
+    }
+}
+
+fn align_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) & !(align - 1)
+}
+
+fn parse_u64_any(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+#[test]
+fn test_qemu_guest_layout() {
+    let q35 = QemuCmdline::parse(b"qemu-system-x86_64\0-machine\0pc-q35-5.2,accel=kvm\0-m\x008G\0-object\0memory-backend-ram,id=mem1,size=1G\0-device\0pc-dimm,id=dimm1,memdev=mem1\0");
+    assert_eq!(q35.machine, MachineType::Q35);
+    assert_eq!(q35.boot_blocks(), vec![8 * GIB]);
+    assert_eq!(q35.dimm_blocks(), vec![GIB]);
+    let regions = q35.guest_regions(&[(0x7f0000000000, 8 * GIB, 0)], &[(0x7e0000000000, GIB, 1)]);
+    assert_eq!((regions[0].start, regions[0].size), (0, 2 * GIB));
+    assert_eq!(regions[1].start, FOUR_GIB);
+    assert_eq!(regions[1].host, 0x7f0000000000 + 2 * GIB);
+    assert_eq!((regions[2].start, regions[2].size), (10 * GIB, GIB));
+
+    // Two NUMA nodes on i440fx, the split at 3G falls inside the first node
+    let pc = QemuCmdline::parse(b"qemu-system-x86_64\0-m\x006144\0-object\0memory-backend-ram,id=n0,size=4G\0-numa\0node,nodeid=0,memdev=n0\0-object\0memory-backend-memfd,id=n1,size=2G\0-numa\0node,nodeid=1,memdev=n1\0");
+    assert_eq!(pc.ram_size, Some(6 * GIB));
+    assert_eq!(pc.boot_blocks(), vec![4 * GIB, 2 * GIB]);
+    assert!(pc.backends[1].memfd && pc.backends[1].share);
+    let regions = pc.guest_regions(
+        &[(0x1000000000, 4 * GIB, 0), (0x2000000000, 2 * GIB, 1)],
+        &[],
+    );
+    assert_eq!((regions[0].start, regions[0].size), (0, 3 * GIB));
+    assert_eq!((regions[1].start, regions[1].size), (FOUR_GIB, GIB));
+    assert_eq!(regions[1].host, 0x1000000000 + 3 * GIB);
+    assert_eq!(regions[2].size, 0);
+    assert_eq!((regions[3].start, regions[3].host), (5 * GIB, 0x2000000000));
+
+    let backends = QemuCmdline::parse(b"qemu-system-x86_64\0-m\x008G\0-object\0memory-backend-file,id=pc.ram,size=8G,mem-path=/dev/hugepages/win10,share=on\0-object\0memory-backend-memfd,id=mem1,size=1G,share=off\0-object\0rng-random,id=rng0\0").backends;
+    assert_eq!(backends.len(), 2);
+    assert_eq!(backends[0].id.as_deref(), Some("pc.ram"));
+    assert_eq!(
+        backends[0].mem_path.as_deref(),
+        Some("/dev/hugepages/win10")
+    );
+    assert!(backends[0].share && !backends[0].memfd);
+    assert!(backends[1].memfd && !backends[1].share);
+
+    let libvirt = QemuCmdline::parse(b"qemu-system-x86_64\0-name\0guest=win10,debug-threads=on\0-uuid\x005b1e4c2a-9f3d-4e2b-8a7c-1d2e3f405162\0-chardev\0socket,id=charmonitor,path=/var/lib/libvirt/qemu/domain-1-win10/monitor.sock,server=on,wait=off\0-mon\0chardev=charmonitor,id=monitor,mode=control\0-qmp\0unix:/tmp/win10.qmp,server,nowait\0");
+    assert_eq!(libvirt.name.as_deref(), Some("win10"));
+    assert_eq!(
+        libvirt.uuid.as_deref(),
+        Some("5b1e4c2a-9f3d-4e2b-8a7c-1d2e3f405162")
+    );
+    // Only the explicit -qmp socket, never libvirt's monitor
+    assert_eq!(libvirt.qmp_sockets, vec!["/tmp/win10.qmp".to_string()]);
+}
@@ -0,0 +1,424 @@
+use crate::backend::regions::PhysRegion;
+use crate::error::{Error, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// A guest-physical range backed by a RAM block, as listed by `info mtree -f`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RamMapping {
+    pub start: u64,
+    pub size: u64,
+    pub block: String,
+    pub block_offset: u64,
+}
+
+/// An entry of `query-memory-devices` (pc-dimm, nvdimm, virtio-mem...).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryDeviceInfo {
+    pub kind: String,
+    pub id: Option<String>,
+    /// RAM block name of the backing memdev
+    pub memdev: Option<String>,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Minimal client for the QEMU Machine Protocol over a unix socket
+/// (`-qmp unix:/path,server,nowait`).
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl QmpClient {
+    /// Connects, reads the greeting and leaves capabilities negotiation mode.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<QmpClient> {
+        let stream = UnixStream::connect(path.as_ref()).map_err(|e| Error::io(&path, e))?;
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let writer = stream.try_clone().map_err(|e| Error::io(&path, e))?;
+        let mut client = QmpClient {
+            reader: BufReader::new(stream),
+            writer,
+        };
+        match client.read_message() {
+            Some(ref greeting) if greeting.get("QMP").is_some() => {}
+            _ => {
+                return Err(Error::Qmp(format!(
+                    "{} did not greet with QMP",
+                    path.as_ref().display()
+                )));
+            }
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    fn read_message(&mut self) -> Option<Value> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => serde_json::from_str(&line).ok(),
+        }
+    }
+
+    /// Runs `command` and returns its `return` value, skipping any asynchronous events
+    /// that arrive first. Errors reported by qemu come back as `Error::Qmp`.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(args) = arguments {
+            request["arguments"] = args;
+        }
+        let mut bytes = request.to_string().into_bytes();
+        bytes.push(b'\n');
+        if let Err(e) = self.writer.write_all(&bytes) {
+            return Err(Error::Qmp(format!("failed to send {}: {}", command, e)));
+        }
+        loop {
+            let mut msg = match self.read_message() {
+                Some(m) => m,
+                None => {
+                    return Err(Error::Qmp(format!(
+                        "connection closed while waiting for {}",
+                        command
+                    )));
+                }
+            };
+            if let Some(ret) = msg.get_mut("return") {
+                return Ok(ret.take());
+            }
+            if let Some(err) = msg.get("error") {
+                return Err(Error::Qmp(format!(
+                    "{} failed: {}",
+                    command,
+                    err["desc"].as_str().unwrap_or("unknown error")
+                )));
+            }
+        }
+    }
+
+    /// Runs a human monitor command (e.g. `info mtree -f`) and returns its output.
+    pub fn hmp(&mut self, command_line: &str) -> Result<String> {
+        match self.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": command_line })),
+        )? {
+            Value::String(s) => Ok(s),
+            _ => Err(Error::Qmp(format!(
+                "{} returned something other than text",
+                command_line
+            ))),
+        }
+    }
+
+    pub fn query_memory_devices(&mut self) -> Result<Vec<MemoryDeviceInfo>> {
+        match self.execute("query-memory-devices", None)? {
+            Value::Array(devices) => Ok(devices.iter().map(parse_memory_device).collect()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Guest-physical RAM ranges from qemu's flattened `memory` address space.
+    pub fn ram_mappings(&mut self) -> Result<Vec<RamMapping>> {
+        Ok(parse_flat_mtree(&self.hmp("info mtree -f")?))
+    }
+
+    /// Whether the vCPUs are currently running, per `query-status`.
+    pub fn query_running(&mut self) -> Result<bool> {
+        match self.execute("query-status", None)?["running"].as_bool() {
+            Some(r) => Ok(r),
+            None => Err(Error::Qmp("query-status has no running flag".to_string())),
+        }
+    }
+
+    /// Size of every RAM block the guest-physical ranges point into: the furthest
+    /// offset used in the flat view, or the device size for memory devices.
+    pub fn ram_blocks(mappings: &[RamMapping], devices: &[MemoryDeviceInfo]) -> Vec<(String, u64)> {
+        let mut blocks: Vec<(String, u64)> = Vec::new();
+        let mut grow = |name: &str, end: u64| match blocks.iter_mut().find(|b| b.0 == name) {
+            Some(b) => b.1 = std::cmp::max(b.1, end),
+            None => blocks.push((name.to_string(), end)),
+        };
+        for m in mappings.iter() {
+            grow(&m.block, m.block_offset + m.size);
+        }
+        for d in devices.iter() {
+            if let Some(memdev) = &d.memdev {
+                grow(memdev, d.size);
+            }
+        }
+        blocks
+    }
+}
+
+/// Keeps the guest's vCPUs stopped while alive. They are resumed on drop,
+/// unless they were already stopped when the guard was taken.
+pub struct FreezeGuard {
+    qmp: QmpClient,
+    resume: bool,
+}
+
+impl FreezeGuard {
+    pub fn new<P: AsRef<Path>>(socket: P) -> Result<FreezeGuard> {
+        let mut qmp = QmpClient::connect(socket)?;
+        let running = qmp.query_running()?;
+        if running {
+            qmp.execute("stop", None)?;
+        }
+        Ok(FreezeGuard {
+            qmp,
+            resume: running,
+        })
+    }
+}
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        if self.resume {
+            if let Err(e) = self.qmp.execute("cont", None) {
+                error!(
+                    "Failed to resume the guest, run `cont` on its monitor: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn parse_memory_device(dev: &Value) -> MemoryDeviceInfo {
+    let data = &dev["data"];
+    MemoryDeviceInfo {
+        kind: dev["type"].as_str().unwrap_or("").to_string(),
+        id: data["id"].as_str().map(String::from),
+        // "/objects/mem1" names the RAM block "mem1"
+        memdev: data["memdev"]
+            .as_str()
+            .map(|m| m.trim_start_matches("/objects/").to_string()),
+        addr: data["addr"].as_u64().unwrap_or(0),
+        size: data["size"].as_u64().unwrap_or(0),
+    }
+}
+
+/// Extracts the RAM-backed ranges of the flat view serving the `memory` address space.
+/// ROM-mode windows into a RAM block (e.g. the PAM shadows of `pc.ram`) are kept as well.
+pub fn parse_flat_mtree(text: &str) -> Vec<RamMapping> {
+    let mut entries = Vec::new();
+    let mut ram_blocks = Vec::new();
+    let mut in_memory_view = false;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("FlatView") {
+            in_memory_view = false;
+            continue;
+        }
+        if trimmed.starts_with("AS \"memory\"") {
+            in_memory_view = true;
+            continue;
+        }
+        if !in_memory_view {
+            continue;
+        }
+        // 0000000100000000-000000023fffffff (prio 0, ram): pc.ram @0000000080000000 KVM
+        let (range, rest) = match trimmed.find(' ') {
+            Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+            None => continue,
+        };
+        let mut bounds = range.splitn(2, '-');
+        let (start, last) = match (bounds.next(), bounds.next()) {
+            (Some(s), Some(l)) => match (u64::from_str_radix(s, 16), u64::from_str_radix(l, 16)) {
+                (Ok(s), Ok(l)) => (s, l),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let (kind, target) = match (rest.find(", "), rest.find("): ")) {
+            (Some(k), Some(t)) if k < t => (&rest[k + 2..t], &rest[t + 3..]),
+            _ => continue,
+        };
+        if kind != "ram" && kind != "rom" {
+            continue;
+        }
+        let mut tokens = target.split_whitespace();
+        let block = match tokens.next() {
+            Some(b) => b.to_string(),
+            None => continue,
+        };
+        let block_offset = tokens
+            .find(|t| t.starts_with('@'))
+            .and_then(|t| u64::from_str_radix(&t[1..], 16).ok())
+            .unwrap_or(0);
+        if kind == "ram" && !ram_blocks.contains(&block) {
+            ram_blocks.push(block.clone());
+        }
+        entries.push(RamMapping {
+            start,
+            size: last - start + 1,
+            block,
+            block_offset,
+        });
+    }
+    entries.retain(|e| ram_blocks.contains(&e.block));
+    entries
+}
+
+/// Turns the flat-view ranges into regions, given where each RAM block lives on the host
+/// and the index of its host mapping. Ranges of blocks missing from `block_hosts` are
+/// left out.
+pub fn ram_regions(
+    mappings: &[RamMapping],
+    block_hosts: &HashMap<String, (u64, usize)>,
+) -> Vec<PhysRegion> {
+    mappings
+        .iter()
+        .filter_map(|m| {
+            block_hosts.get(&m.block).map(|(host, mapping)| PhysRegion {
+                start: m.start,
+                size: m.size,
+                host: host + m.block_offset,
+                mapping: *mapping,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_qmp_fake_server() {
+    use std::os::unix::net::UnixListener;
+
+    const MTREE: &str = "FlatView #0\n AS \"I/O\", root: io\n Root memory region: io\n  0000000000000000-0000000000000007 (prio 0, i/o): dma-chan\n\nFlatView #1\n AS \"memory\", root: system\n AS \"cpu-memory-0\", root: system\n Root memory region: system\n  0000000000000000-000000000009ffff (prio 0, ram): pc.ram KVM\n  00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem\n  00000000000c0000-00000000000dffff (prio 1, rom): pc.ram @00000000000c0000 KVM\n  00000000000e0000-00000000000fffff (prio 1, rom): pc.bios @0000000000020000 KVM\n  0000000000100000-000000007fffffff (prio 0, ram): pc.ram @0000000000100000 KVM\n  00000000fffc0000-00000000ffffffff (prio 0, romd): system.flash0 KVM\n  0000000100000000-000000027fffffff (prio 0, ram): pc.ram @0000000080000000 KVM\n  0000000280000000-00000002bfffffff (prio 0, ram): mem1 KVM\n";
+
+    let path = std::env::temp_dir().join(format!("libvirtdma-qmp-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let reader = BufReader::new(stream);
+        writer
+            .write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"micro\": 0, \"minor\": 2, \"major\": 5}}, \"capabilities\": []}}\n")
+            .unwrap();
+        for line in reader.lines() {
+            let req: Value = serde_json::from_str(&line.unwrap()).unwrap();
+            let ret = match req["execute"].as_str().unwrap() {
+                "qmp_capabilities" => json!({}),
+                "query-memory-devices" => json!([{ "type": "dimm", "data": {
+                    "id": "dimm1", "memdev": "/objects/mem1", "addr": 0x280000000u64,
+                    "size": 0x40000000u64, "slot": 0, "node": 0 } }]),
+                "human-monitor-command" => json!(MTREE),
+                _ => {
+                    writer
+                        .write_all(
+                            b"{\"error\": {\"class\": \"CommandNotFound\", \"desc\": \"nope\"}}\n",
+                        )
+                        .unwrap();
+                    continue;
+                }
+            };
+            // An event in between must not be taken for the reply
+            writer
+                .write_all(b"{\"event\": \"RTC_CHANGE\", \"data\": {\"offset\": 0}}\n")
+                .unwrap();
+            writer
+                .write_all(format!("{}\n", json!({ "return": ret })).as_bytes())
+                .unwrap();
+        }
+    });
+
+    let mut qmp = QmpClient::connect(&path).unwrap();
+    assert!(qmp.execute("bogus", None).is_err());
+    let devices = qmp.query_memory_devices().unwrap();
+    assert_eq!(devices[0].memdev.as_deref(), Some("mem1"));
+    assert_eq!(devices[0].addr, 0x280000000);
+
+    let mappings = qmp.ram_mappings().unwrap();
+    assert_eq!(mappings.len(), 5);
+    assert!(mappings.iter().all(|m| m.block != "pc.bios"));
+    assert_eq!(
+        QmpClient::ram_blocks(&mappings, &devices),
+        vec![
+            ("pc.ram".to_string(), 0x200000000),
+            ("mem1".to_string(), 0x40000000)
+        ]
+    );
+
+    let mut hosts = HashMap::new();
+    hosts.insert("pc.ram".to_string(), (0x7f0000000000u64, 0));
+    hosts.insert("mem1".to_string(), (0x7e0000000000u64, 1));
+    let regions = ram_regions(&mappings, &hosts);
+    assert_eq!(regions[3].start, 0x100000000);
+    assert_eq!(regions[3].host, 0x7f0080000000);
+    assert_eq!(regions[4].host, 0x7e0000000000);
+
+    drop(qmp);
+    server.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_freeze_guard() {
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+
+    let path = std::env::temp_dir().join(format!("libvirtdma-freeze-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let (tx, rx) = channel();
+    let server = std::thread::spawn(move || {
+        let mut running = true;
+        for _ in 0..3 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            writer
+                .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+                .unwrap();
+            for line in BufReader::new(stream).lines() {
+                let req: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let command = req["execute"].as_str().unwrap().to_string();
+                let ret = match command.as_str() {
+                    "query-status" => json!({ "running": running, "status": "running" }),
+                    "stop" => {
+                        running = false;
+                        json!({})
+                    }
+                    "cont" => {
+                        running = true;
+                        json!({})
+                    }
+                    _ => json!({}),
+                };
+                tx.send(command).unwrap();
+                writer
+                    .write_all(format!("{}\n", json!({ "return": ret })).as_bytes())
+                    .unwrap();
+            }
+        }
+    });
+    let log = |rx: &std::sync::mpsc::Receiver<String>| rx.try_iter().collect::<Vec<String>>();
+
+    // Running guest: stopped for the guard's lifetime, resumed on drop
+    let guard = FreezeGuard::new(&path).unwrap();
+    assert_eq!(log(&rx), vec!["qmp_capabilities", "query-status", "stop"]);
+    drop(guard);
+    assert_eq!(log(&rx), vec!["cont"]);
+
+    // Guest paused by someone else: the guard leaves it paused
+    let mut qmp = QmpClient::connect(&path).unwrap();
+    qmp.execute("stop", None).unwrap();
+    drop(qmp);
+    let guard = FreezeGuard::new(&path).unwrap();
+    drop(guard);
+    server.join().unwrap();
+    assert_eq!(
+        log(&rx),
+        vec![
+            "qmp_capabilities",
+            "stop",
+            "qmp_capabilities",
+            "query-status"
+        ]
+    );
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,44 @@
+use crate::backend::PhysicalMemory;
+use crate::error::{Error, Result};
+use memmap::{Mmap, MmapOptions};
+use std::fs::File;
+use std::path::Path;
+
+/// Flat guest RAM image (e.g. QEMU `pmemsave`), where file offset equals guest-physical address.
+/// The file is mapped read-only, so writes always fail.
+pub struct RawImage {
+    map: Mmap,
+}
+
+impl RawImage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RawImage> {
+        let file = File::open(path.as_ref()).map_err(|e| Error::io(&path, e))?;
+        match unsafe { MmapOptions::new().map(&file) } {
+            Ok(map) => Ok(RawImage { map }),
+            Err(e) => Err(Error::io(&path, e)),
+        }
+    }
+}
+
+impl PhysicalMemory for RawImage {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let end = match addr.checked_add(buf.len() as u64) {
+            Some(e) if e <= self.map.len() as u64 => e,
+            _ => return false,
+        };
+        buf.copy_from_slice(&self.map[addr as usize..end as usize]);
+        true
+    }
+
+    fn phys_write(&self, _addr: u64, _buf: &[u8]) -> bool {
+        false
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+}
@@ -0,0 +1,114 @@
+use std::ops::Range;
+
+/// A contiguous guest-physical range and where it lives on our side.
+#[derive(Debug, Clone, Copy)]
+pub struct PhysRegion {
+    pub start: u64,
+    pub size: u64,
+    /// Address of the first byte of the region in the backing store
+    /// (a pointer in our address space for mapped backends).
+    pub host: u64,
+    /// Index of the host mapping holding the region, for backends made of several; 0
+    /// otherwise
+    pub mapping: usize,
+}
+
+impl PhysRegion {
+    #[inline(always)]
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// Sorted, non-overlapping guest-physical region table shared by the
+/// backends that can't treat guest RAM as a single flat mapping.
+#[derive(Debug, Clone, Default)]
+pub struct RegionMap {
+    regions: Vec<PhysRegion>,
+}
+
+impl RegionMap {
+    pub fn new(mut regions: Vec<PhysRegion>) -> RegionMap {
+        regions.retain(|r| r.size != 0);
+        regions.sort_by_key(|r| r.start);
+        RegionMap { regions }
+    }
+
+    pub fn regions(&self) -> &[PhysRegion] {
+        &self.regions
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&PhysRegion> {
+        let idx = match self.regions.binary_search_by_key(&addr, |r| r.start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let region = &self.regions[idx];
+        if addr < region.end() {
+            Some(region)
+        } else {
+            None
+        }
+    }
+
+    /// Splits `[addr, addr + len)` at region boundaries and calls `f(host, offset, len)`
+    /// for every piece, `offset` being relative to `addr`. Nothing is visited and false
+    /// is returned if any byte of the range falls into a hole.
+    pub fn walk<F: FnMut(u64, usize, usize)>(&self, addr: u64, len: usize, mut f: F) -> bool {
+        if !self.covers(addr, len) {
+            return false;
+        }
+        let mut cursor = 0usize;
+        while cursor < len {
+            let pa = addr + cursor as u64;
+            let region = self.find(pa).unwrap();
+            let chunk = std::cmp::min((region.end() - pa) as usize, len - cursor);
+            f(region.host + (pa - region.start), cursor, chunk);
+            cursor += chunk;
+        }
+        true
+    }
+
+    pub fn covers(&self, addr: u64, len: usize) -> bool {
+        let end = match addr.checked_add(len as u64) {
+            Some(e) => e,
+            None => return false,
+        };
+        let mut pa = addr;
+        while pa < end {
+            match self.find(pa) {
+                Some(region) => pa = region.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Moves the host address of every region of host mapping `mapping`, which started
+    /// at `from`, to the same offset from `to`.
+    pub fn rebase(&mut self, mapping: usize, from: u64, to: u64) {
+        for r in self.regions.iter_mut().filter(|r| r.mapping == mapping) {
+            r.host = r.host - from + to;
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self.regions.last() {
+            Some(r) => r.end(),
+            None => 0,
+        }
+    }
+
+    pub fn holes(&self) -> Vec<Range<u64>> {
+        let mut holes = Vec::new();
+        let mut last_end = 0u64;
+        for r in self.regions.iter() {
+            if r.start > last_end {
+                holes.push(last_end..r.start);
+            }
+            last_end = std::cmp::max(last_end, r.end());
+        }
+        holes
+    }
+}
@@ -0,0 +1,177 @@
+use crate::backend::guests::VmSelector;
+use crate::backend::kvm::{GuestRam, KvmMemory};
+use crate::backend::qemu::{MemoryBackendArg, QemuCmdline};
+use crate::backend::PhysicalMemory;
+use crate::error::{Error, Result};
+use crate::vm::ProcessData;
+use memmap::{MmapMut, MmapOptions};
+use proc_maps::{MapRange, Pid};
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Guest RAM that qemu keeps in shared files (hugetlbfs, tmpfs, memfd), mapped
+/// straight into our address space. Zero-copy, and needs neither the kernel module
+/// nor ptrace, only permission to open the backing files.
+pub struct SharedRamMemory {
+    pub ram: GuestRam,
+    pub paths: Vec<PathBuf>,
+    // Keeps the mappings the regions point into alive
+    _maps: Vec<MmapMut>,
+}
+
+impl SharedRamMemory {
+    /// Locates the qemu-kvm process and maps its shared RAM backing files, if it has them.
+    pub fn open(vm: Option<&VmSelector>) -> Result<SharedRamMemory> {
+        Self::from_guest_ram(KvmMemory::create_guest_ram(vm)?)
+    }
+
+    /// Maps the backing file of every host mapping in `ram`; fails unless all of them are shared files.
+    pub fn from_guest_ram(mut ram: GuestRam) -> Result<SharedRamMemory> {
+        let pid = ram.pid as u64;
+        let pmaps = proc_maps::get_process_maps(pid as Pid)
+            .map_err(|e| Error::io(format!("/proc/{}/maps", pid), e))?;
+        let backends = match QemuCmdline::read(pid) {
+            Ok(cmd) => cmd.backends,
+            Err(_) => Vec::new(),
+        };
+        let mut paths = Vec::new();
+        let mut maps = Vec::new();
+        for idx in 0..ram.mappings.len() {
+            let mapping = ram.mappings[idx];
+            let range = match pmaps.iter().find(|m| {
+                (m.start() as u64) <= mapping.maps_start
+                    && mapping.maps_start < m.start() as u64 + m.size() as u64
+            }) {
+                Some(r) => r,
+                None => {
+                    return Err(Error::Backend(format!(
+                        "Guest RAM at 0x{:x} is no longer mapped in qemu-kvm (pid {})",
+                        mapping.maps_start, pid
+                    )));
+                }
+            };
+            let path = match Self::find_backing_path(pid, range, &backends) {
+                Some(p) => p,
+                None => {
+                    return Err(Error::Backend(format!(
+                        "Guest RAM of qemu-kvm (pid {}) is not backed by a shared file",
+                        pid
+                    )));
+                }
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .map_err(|e| Error::io(&path, e))?;
+            let offset = range.offset as u64 + (mapping.maps_start - range.start() as u64);
+            let map = Self::map_backing(&file, &path, offset, mapping.maps_size)?;
+            // Same shape the kernel module hands back: the RAM now lives in our own address space
+            ram.remap(
+                idx,
+                ProcessData {
+                    maps_start: map.as_ptr() as u64,
+                    maps_size: mapping.maps_size,
+                    pid: std::process::id() as i32,
+                },
+            );
+            paths.push(path);
+            maps.push(map);
+        }
+        Ok(SharedRamMemory {
+            ram,
+            paths,
+            _maps: maps,
+        })
+    }
+
+    fn map_backing(file: &File, path: &Path, offset: u64, size: u64) -> Result<MmapMut> {
+        unsafe {
+            MmapOptions::new()
+                .offset(offset)
+                .len(size as usize)
+                .map_mut(file)
+        }
+        .map_err(|e| Error::io(path, e))
+    }
+
+    fn find_backing_path(
+        pid: u64,
+        ram: &MapRange,
+        backends: &[MemoryBackendArg],
+    ) -> Option<PathBuf> {
+        if !ram.flags.contains('s') {
+            return None;
+        }
+        let name = match ram.filename() {
+            Some(n) if n.starts_with('/') => n.clone(),
+            _ => return None,
+        };
+        // An explicit mem-path file is opened by name, a mem-path directory leaves an
+        // unlinked file behind, and memfds have no name at all; those two go through the fd table
+        let explicit = backends
+            .iter()
+            .filter(|b| b.share)
+            .filter_map(|b| b.mem_path.as_ref())
+            .any(|p| p == &name);
+        if explicit || (!name.starts_with("/memfd:") && Path::new(&name).is_file()) {
+            return Some(PathBuf::from(name));
+        }
+        Self::find_fd_path(pid, &name)
+    }
+
+    fn find_fd_path(pid: u64, name: &str) -> Option<PathBuf> {
+        let fds = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+            Ok(d) => d,
+            Err(_) => return None,
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            match std::fs::read_link(fd.path()) {
+                Ok(target) if target.to_string_lossy().starts_with(name) => {
+                    return Some(fd.path());
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl PhysicalMemory for SharedRamMemory {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        self.ram
+            .regions
+            .walk(addr, buf.len(), |host, offset, len| unsafe {
+                libc::memcpy(
+                    buf[offset..].as_mut_ptr() as *mut libc::c_void,
+                    host as *mut libc::c_void,
+                    len as libc::size_t,
+                );
+            })
+    }
+
+    fn phys_write(&self, addr: u64, buf: &[u8]) -> bool {
+        self.ram
+            .regions
+            .walk(addr, buf.len(), |host, offset, len| unsafe {
+                libc::memcpy(
+                    host as *mut libc::c_void,
+                    buf[offset..].as_ptr() as *mut libc::c_void,
+                    len as libc::size_t,
+                );
+            })
+    }
+
+    fn size(&self) -> u64 {
+        self.ram.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.ram.regions.holes()
+    }
+
+    fn monitor_socket(&self) -> Option<PathBuf> {
+        self.ram.monitor.clone()
+    }
+}
@@ -0,0 +1,329 @@
+use crate::backend::regions::{PhysRegion, RegionMap};
+use crate::backend::PhysicalMemory;
+use crate::error::{self, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{Mmap, MmapOptions};
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const VMSS_MAGICS: [u32; 4] = [0xbed2bed0, 0xbad1bad1, 0xbed2bed2, 0xbed3bed3];
+// Older snapshots store the length of long tag payloads as 32-bit values
+const VMSS_MAGIC_V0: u32 = 0xbed2bed0;
+const VMSS_HEADER_SIZE: usize = 12;
+const VMSS_GROUP_SIZE: usize = 80;
+const VMSS_GROUP_NAME_SIZE: usize = 64;
+const PAGE_SIZE: u64 = 0x1000;
+
+pub fn is_vmss_magic(magic: &[u8]) -> bool {
+    magic.len() >= 4 && VMSS_MAGICS.contains(&LittleEndian::read_u32(magic))
+}
+
+/// VMware suspended state or snapshot: a `.vmem` file with guest RAM, and a
+/// `.vmss`/`.vmsn` file whose "memory" group describes where each run of `.vmem`
+/// pages sits in guest-physical memory.
+pub struct VmwareSnapshot {
+    map: Mmap,
+    regions: RegionMap,
+}
+
+impl VmwareSnapshot {
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(vmem: P, meta: Q) -> error::Result<VmwareSnapshot> {
+        let meta_data = std::fs::read(meta.as_ref()).map_err(|e| Error::io(&meta, e))?;
+        let tags = Self::parse_memory_tags(&meta_data).map_err(|e| {
+            Error::Image(format!(
+                "Unable to parse VMware state file {}: {}",
+                meta.as_ref().display(),
+                e
+            ))
+        })?;
+
+        let file = File::open(vmem.as_ref()).map_err(|e| Error::io(&vmem, e))?;
+        let map = unsafe { MmapOptions::new().map(&file) }.map_err(|e| Error::io(&vmem, e))?;
+
+        let base = map.as_ptr() as u64;
+        let mut regions = Vec::new();
+        match tags.get(&("regionsCount".to_string(), vec![])) {
+            None | Some(0) => regions.push(PhysRegion {
+                start: 0,
+                size: map.len() as u64,
+                host: base,
+                mapping: 0,
+            }),
+            Some(count) => {
+                for i in 0..*count as u32 {
+                    let tag = |name: &str| tags.get(&(name.to_string(), vec![i])).cloned();
+                    let (ppn, page_num, page_count) =
+                        match (tag("regionPPN"), tag("regionPageNum"), tag("regionSize")) {
+                            (Some(a), Some(b), Some(c)) => (a, b, c),
+                            _ => {
+                                return Err(Error::Image(format!(
+                                    "VMware state file is missing the description of region {}",
+                                    i
+                                )));
+                            }
+                        };
+                    let end = page_num
+                        .checked_add(page_count)
+                        .and_then(|pages| pages.checked_mul(PAGE_SIZE));
+                    match end {
+                        Some(end) if end <= map.len() as u64 => {}
+                        _ => {
+                            return Err(Error::Image(format!(
+                                "VMware region {} points past the end of the .vmem file",
+                                i
+                            )));
+                        }
+                    }
+                    let size = page_count * PAGE_SIZE;
+                    let start = match ppn.checked_mul(PAGE_SIZE) {
+                        Some(start) if start.checked_add(size).is_some() => start,
+                        _ => {
+                            return Err(Error::Image(format!(
+                                "VMware region {} has an out of range guest-physical address",
+                                i
+                            )));
+                        }
+                    };
+                    regions.push(PhysRegion {
+                        start,
+                        size,
+                        host: base + page_num * PAGE_SIZE,
+                        mapping: 0,
+                    });
+                }
+            }
+        };
+        Ok(VmwareSnapshot {
+            map,
+            regions: RegionMap::new(regions),
+        })
+    }
+
+    /// Opens a `.vmem` or `.vmss`/`.vmsn` file together with its sibling of the same name.
+    pub fn open_pair<P: AsRef<Path>>(path: P) -> error::Result<VmwareSnapshot> {
+        let path = path.as_ref();
+        let is_vmem = match path.extension() {
+            Some(ext) => ext.eq_ignore_ascii_case("vmem"),
+            None => false,
+        };
+        if is_vmem {
+            match Self::state_file(path) {
+                Some(meta) => Self::open(path, meta),
+                None => Err(Error::Image(format!(
+                    "Unable to find a .vmss or .vmsn file next to {}",
+                    path.display()
+                ))),
+            }
+        } else {
+            match Self::find_sibling(path, &["vmem"]) {
+                Some(vmem) => Self::open(vmem, path),
+                None => Err(Error::Image(format!(
+                    "Unable to find a .vmem file next to {}",
+                    path.display()
+                ))),
+            }
+        }
+    }
+
+    /// The `.vmss` or `.vmsn` file next to a `.vmem`, if there is one.
+    pub fn state_file(vmem: &Path) -> Option<PathBuf> {
+        Self::find_sibling(vmem, &["vmss", "vmsn"])
+    }
+
+    fn find_sibling(path: &Path, extensions: &[&str]) -> Option<PathBuf> {
+        extensions
+            .iter()
+            .map(|ext| path.with_extension(ext))
+            .find(|p| p.exists())
+    }
+
+    pub fn regions(&self) -> &RegionMap {
+        &self.regions
+    }
+
+    /// Collects the integer tags of the "memory" group, keyed by (name, indices).
+    fn parse_memory_tags(data: &[u8]) -> Result<HashMap<(String, Vec<u32>), u64>, String> {
+        if data.len() < VMSS_HEADER_SIZE || !is_vmss_magic(data) {
+            return Err("missing VMSS magic".to_string());
+        }
+        let long_len_size = if LittleEndian::read_u32(data) == VMSS_MAGIC_V0 {
+            4
+        } else {
+            8
+        };
+        let group_count = LittleEndian::read_u32(&data[8..]) as usize;
+
+        let mut memory_group = None;
+        for i in 0..group_count {
+            let begin = VMSS_HEADER_SIZE + i * VMSS_GROUP_SIZE;
+            let group = match data.get(begin..begin + VMSS_GROUP_SIZE) {
+                Some(g) => g,
+                None => return Err(format!("group {} is out of bounds", i)),
+            };
+            let name = &group[..VMSS_GROUP_NAME_SIZE];
+            let name_len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            if &name[..name_len] == b"memory" {
+                memory_group =
+                    Some(LittleEndian::read_u64(&group[VMSS_GROUP_NAME_SIZE..]) as usize);
+            }
+        }
+        let mut offset = match memory_group {
+            Some(o) => o,
+            None => return Err("no memory group".to_string()),
+        };
+
+        // Offsets come from the file, so a range that overflows is as truncated as one
+        // past the end
+        let bytes = |start: usize, len: usize| data.get(start..start.checked_add(len)?);
+        let mut tags = HashMap::new();
+        loop {
+            let (flags, name_len) = match bytes(offset, 2) {
+                Some(b) => (b[0], b[1] as usize),
+                None => return Err("truncated tag list".to_string()),
+            };
+            if flags == 0 && name_len == 0 {
+                break;
+            }
+            let index_count = ((flags >> 6) & 3) as usize;
+            let data_len = (flags & 0x3f) as usize;
+            let header_len = 2 + name_len + index_count * 4;
+            let header = match bytes(offset, header_len) {
+                Some(h) => h,
+                None => return Err("truncated tag".to_string()),
+            };
+            let name = String::from_utf8_lossy(&header[2..2 + name_len]).to_string();
+            let indices: Vec<u32> = (0..index_count)
+                .map(|i| LittleEndian::read_u32(&header[2 + name_len + i * 4..]))
+                .collect();
+            offset += header_len;
+
+            if data_len == 62 || data_len == 63 {
+                // Long payload: size, in-memory size, two bytes of padding, then the data
+                let size = match bytes(offset, long_len_size) {
+                    Some(b) => LittleEndian::read_uint(b, long_len_size) as usize,
+                    None => return Err(format!("truncated length of tag {}", name)),
+                };
+                offset = match size.checked_add(2 * long_len_size + 2) {
+                    Some(skip) => match offset.checked_add(skip) {
+                        Some(o) => o,
+                        None => return Err("truncated tag".to_string()),
+                    },
+                    None => return Err("truncated tag".to_string()),
+                };
+            } else {
+                match bytes(offset, data_len) {
+                    Some(b) if data_len > 0 && data_len <= 8 => {
+                        tags.insert((name, indices), LittleEndian::read_uint(b, data_len));
+                    }
+                    Some(_) => {}
+                    None => return Err(format!("truncated value of tag {}", name)),
+                }
+                offset += data_len;
+            }
+        }
+        Ok(tags)
+    }
+}
+
+impl PhysicalMemory for VmwareSnapshot {
+    fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let base = self.map.as_ptr() as u64;
+        let map = &self.map;
+        self.regions.walk(addr, buf.len(), |host, offset, len| {
+            let file_offset = (host - base) as usize;
+            buf[offset..offset + len].copy_from_slice(&map[file_offset..file_offset + len]);
+        })
+    }
+
+    fn phys_write(&self, _addr: u64, _buf: &[u8]) -> bool {
+        false
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn size(&self) -> u64 {
+        self.regions.size()
+    }
+
+    fn holes(&self) -> Vec<Range<u64>> {
+        self.regions.holes()
+    }
+}
+
+#[test]
+fn test_vmware_region_table() {
+    fn tag(out: &mut Vec<u8>, name: &str, index: Option<u32>, value: u32) {
+        let index_bits = if index.is_some() { 1 << 6 } else { 0 };
+        out.push(index_bits | 4);
+        out.push(name.len() as u8);
+        out.extend_from_slice(name.as_bytes());
+        if let Some(i) = index {
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let mut vmss = Vec::new();
+    vmss.extend_from_slice(&0xbed3bed3u32.to_le_bytes());
+    vmss.extend_from_slice(&0u32.to_le_bytes());
+    vmss.extend_from_slice(&1u32.to_le_bytes());
+    let mut group = [0u8; VMSS_GROUP_SIZE];
+    group[..6].copy_from_slice(b"memory");
+    LittleEndian::write_u64(
+        &mut group[VMSS_GROUP_NAME_SIZE..],
+        (VMSS_HEADER_SIZE + VMSS_GROUP_SIZE) as u64,
+    );
+    vmss.extend_from_slice(&group);
+    // A long blob tag that must be skipped over
+    vmss.extend_from_slice(&[62, 4]);
+    vmss.extend_from_slice(b"blob");
+    vmss.extend_from_slice(&3u64.to_le_bytes());
+    vmss.extend_from_slice(&3u64.to_le_bytes());
+    vmss.extend_from_slice(&[0, 0, 0xaa, 0xbb, 0xcc]);
+    tag(&mut vmss, "regionsCount", None, 2);
+    // .vmem pages 0-1 at PA 0, page 2 at PA 4GiB
+    for (i, (ppn, page_num, pages)) in [(0u32, 0u32, 2u32), (0x100000, 2, 1)].iter().enumerate() {
+        tag(&mut vmss, "regionPPN", Some(i as u32), *ppn);
+        tag(&mut vmss, "regionPageNum", Some(i as u32), *page_num);
+        tag(&mut vmss, "regionSize", Some(i as u32), *pages);
+    }
+    vmss.extend_from_slice(&[0, 0]);
+
+    let vmem: Vec<u8> = (0..0x3000u32).map(|i| (i / 0x1000) as u8 + 1).collect();
+    let stem = std::env::temp_dir().join(format!("libvirtdma-vmware-{}", std::process::id()));
+    std::fs::write(stem.with_extension("vmss"), &vmss).unwrap();
+    std::fs::write(stem.with_extension("vmem"), &vmem).unwrap();
+    let snapshot = VmwareSnapshot::open_pair(stem.with_extension("vmem"));
+    // A broken state file is an error rather than a reason to read the .vmem flat
+    std::fs::write(stem.with_extension("vmss"), &vmss[..20]).unwrap();
+    let broken = crate::backend::open_image(stem.with_extension("vmem")).is_err();
+    std::fs::remove_file(stem.with_extension("vmss")).unwrap();
+    let flat = crate::backend::open_image(stem.with_extension("vmem")).map(|m| m.size());
+    std::fs::remove_file(stem.with_extension("vmem")).unwrap();
+    let snapshot = snapshot.unwrap();
+    assert!(broken);
+    assert_eq!(flat.unwrap(), 0x3000);
+
+    assert_eq!(snapshot.holes(), vec![0x2000..0x100000000]);
+    let mut buf = [0u8; 2];
+    assert!(snapshot.phys_read(0xfff, &mut buf));
+    assert_eq!(buf, [1, 2]);
+    assert!(snapshot.phys_read(0x100000000, &mut buf));
+    assert_eq!(buf, [3, 3]);
+
+    // Sizes and offsets that overflow are reported as truncated, not wrapped
+    let blob_size = VMSS_HEADER_SIZE + VMSS_GROUP_SIZE + 2 + 4;
+    let mut corrupt = vmss.clone();
+    LittleEndian::write_u64(&mut corrupt[blob_size..], u64::MAX);
+    assert!(VmwareSnapshot::parse_memory_tags(&corrupt).is_err());
+    let mut corrupt = vmss;
+    LittleEndian::write_u64(
+        &mut corrupt[VMSS_HEADER_SIZE + VMSS_GROUP_NAME_SIZE..],
+        u64::MAX,
+    );
+    assert!(VmwareSnapshot::parse_memory_tags(&corrupt).is_err());
+}
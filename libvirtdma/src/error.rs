@@ -0,0 +1,200 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Everything that can go wrong while binding to a guest or reading from it.
+#[derive(Debug)]
+pub enum Error {
+    /// A file (image, backing file, device node, socket) couldn't be opened or read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// No running VM to attach to, or none matching the selector
+    NoGuest(String),
+    /// The guest's RAM couldn't be located or mapped with the chosen access method
+    Backend(String),
+    /// A memory image that isn't in the expected format
+    Image(String),
+    /// The QMP socket refused a connection or a command
+    Qmp(String),
+    /// Kernel discovery failed (DTB, ntoskrnl, PsInitialSystemProcess)
+    Bind(String),
+    /// The virtual address isn't mapped in the address space of `dirbase`
+    Translation {
+        dirbase: u64,
+        address: u64,
+    },
+    /// Guest-physical memory outside of guest RAM, or the backend failed to read it
+    Read {
+        address: u64,
+        len: u64,
+    },
+    Write {
+        address: u64,
+        len: u64,
+    },
+    /// The binding is in `AccessMode::ReadOnly`
+    ReadOnly {
+        address: u64,
+        len: u64,
+    },
+    /// No outstanding patch has this id
+    UnknownPatch(u64),
+    /// Patch `id` can't be reverted before the later patch `later` that overwrote part of it
+    PatchOverlap {
+        id: u64,
+        later: u64,
+    },
+    /// The bytes a patch file expects at `module`+`rva` aren't there
+    PatchMismatch {
+        module: String,
+        rva: u64,
+    },
+    /// The process has no module of this name loaded
+    MissingModule(String),
+    /// Other processes map the physical page behind `address` too, so writing it would
+    /// change them as well
+    SharedPage {
+        address: u64,
+        /// pid and name of each
+        processes: Vec<(u64, String)>,
+    },
+    /// Only the first `read` of `len` bytes at `address` could be read; `source` says why
+    PartialRead {
+        address: u64,
+        len: u64,
+        read: u64,
+        source: Box<Error>,
+    },
+    /// The image at `base` has no valid PE headers or export directory
+    Pe {
+        base: u64,
+        reason: String,
+    },
+    /// No offsets are known for this NT version and build
+    UnsupportedVersion {
+        nt_version: u16,
+        nt_build: u32,
+    },
+    /// A kernel export we depend on is missing
+    MissingExport(String),
+    /// Malformed user input (patterns, memory layouts)
+    Parse(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn io<P: AsRef<Path>>(path: P, source: std::io::Error) -> Error {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    /// `source` if nothing was read at all, otherwise a `PartialRead` wrapping it
+    pub(crate) fn partial_read(address: u64, len: u64, read: u64, source: Error) -> Error {
+        if read == 0 {
+            return source;
+        }
+        Error::PartialRead {
+            address,
+            len,
+            read,
+            source: Box::new(source),
+        }
+    }
+
+    pub(crate) fn pe<S: Into<String>>(base: u64, reason: S) -> Error {
+        Error::Pe {
+            base,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::NoGuest(s) => write!(f, "{}", s),
+            Error::Backend(s) => write!(f, "{}", s),
+            Error::Image(s) => write!(f, "{}", s),
+            Error::Qmp(s) => write!(f, "QMP: {}", s),
+            Error::Bind(s) => write!(f, "unable to bind: {}", s),
+            Error::Translation { dirbase, address } => write!(
+                f,
+                "0x{:x} is not mapped in the address space with DTB 0x{:x}",
+                address, dirbase
+            ),
+            Error::Read { address, len } => write!(
+                f,
+                "unable to read 0x{:x} bytes of guest memory at 0x{:x}",
+                len, address
+            ),
+            Error::Write { address, len } => write!(
+                f,
+                "unable to write 0x{:x} bytes of guest memory at 0x{:x}",
+                len, address
+            ),
+            Error::ReadOnly { address, len } => write!(
+                f,
+                "refusing to write 0x{:x} bytes at 0x{:x}: the binding is read-only",
+                len, address
+            ),
+            Error::UnknownPatch(id) => write!(f, "no outstanding patch #{}", id),
+            Error::PatchOverlap { id, later } => write!(
+                f,
+                "patch #{} is partly overwritten by patch #{}, revert that one first",
+                id, later
+            ),
+            Error::PatchMismatch { module, rva } => write!(
+                f,
+                "the bytes at {}+0x{:x} aren't the ones the patch replaces",
+                module, rva
+            ),
+            Error::MissingModule(name) => write!(f, "module '{}' is not loaded", name),
+            Error::SharedPage { address, processes } => write!(
+                f,
+                "the page behind 0x{:x} is shared with {}; force the write to change it in all of them",
+                address,
+                processes
+                    .iter()
+                    .map(|(pid, name)| format!("{} (pid {})", name, pid))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Error::PartialRead {
+                address,
+                len,
+                read,
+                source,
+            } => write!(
+                f,
+                "read only 0x{:x} of 0x{:x} bytes at 0x{:x}: {}",
+                read, len, address, source
+            ),
+            Error::Pe { base, reason } => write!(f, "bad PE image at 0x{:x}: {}", base, reason),
+            Error::UnsupportedVersion {
+                nt_version,
+                nt_build,
+            } => write!(
+                f,
+                "unsupported Windows version {} (build {})",
+                nt_version, nt_build
+            ),
+            Error::MissingExport(name) => write!(f, "kernel export '{}' not found", name),
+            Error::Parse(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::PartialRead { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
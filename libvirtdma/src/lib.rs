@@ -1,5 +1,6 @@
 #![feature(const_int_pow)]
 #![feature(new_uninit)]
+#![feature(min_const_generics)]
 
 #[macro_use]
 extern crate c2rust_bitfields;
@@ -7,6 +8,14 @@ extern crate c2rust_bitfields;
 #[macro_use]
 extern crate nix;
 
+#[macro_use]
+extern crate log;
+
+pub mod backend;
+pub mod error;
+pub use error::{Error, Result};
+pub mod pod;
+pub use pod::Pod;
 pub mod proc_kernelinfo;
 
 pub mod vm;
@@ -65,6 +74,16 @@ impl<T> TypedRemotePtr<T> {
     }
 }
 
+impl<T: Pod> TypedRemotePtr<T> {
+    pub fn try_read(&self, vm: &VMBinding, offset: i64) -> Result<T> {
+        self.ptr.try_read(vm, offset)
+    }
+
+    pub fn try_vread(&self, vm: &VMBinding, dtb: u64, offset: i64) -> Result<T> {
+        self.ptr.try_vread(vm, dtb, offset)
+    }
+}
+
 impl<T> std::fmt::Debug for TypedRemotePtr<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.ptr.print(f)
@@ -97,6 +116,16 @@ impl RemotePtr {
         vm.vread(dtb, offseted)
     }
 
+    pub fn try_read<T: Pod>(&self, vm: &VMBinding, offset: i64) -> Result<T> {
+        let offseted = self.addr.wrapping_add(offset as u64);
+        vm.try_read(offseted)
+    }
+
+    pub fn try_vread<T: Pod>(&self, vm: &VMBinding, dtb: u64, offset: i64) -> Result<T> {
+        let offseted = self.addr.wrapping_add(offset as u64);
+        vm.try_vread(dtb, offseted)
+    }
+
     pub fn readvec(&self, vm: &VMBinding, offset: i64, len: u64) -> Box<[u8]> {
         let offseted = self.addr.wrapping_add(offset as u64);
         vm.readvec(offseted, len)
@@ -0,0 +1,33 @@
+/// Plain old data: `Copy`, no references, and every bit pattern is a valid value, so a
+/// value can be filled straight from guest memory without any checks.
+///
+/// # Safety
+///
+/// Only implement this for `#[repr(C)]` (or primitive) types made entirely of other `Pod`
+/// types, raw pointers, or unions of those. No `bool`, `char`, enums, references or
+/// anything with a destructor.
+pub unsafe trait Pod: Copy + 'static {}
+
+#[macro_export]
+macro_rules! unsafe_impl_pod {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl $crate::pod::Pod for $t {})*
+    };
+}
+
+unsafe_impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+unsafe impl Pod for crate::RemotePtr {}
+unsafe impl<T: Copy + 'static> Pod for crate::TypedRemotePtr<T> {}
+
+/// A zeroed `T`, to be overwritten with bytes from the guest
+pub(crate) fn zeroed<T: Pod>() -> T {
+    unsafe { std::mem::zeroed() }
+}
+
+/// The bytes backing `value`, for reading into it in place
+pub(crate) fn bytes_of_mut<T: Pod>(value: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(value as *mut T as *mut u8, std::mem::size_of::<T>()) }
+}
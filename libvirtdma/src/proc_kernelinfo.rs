@@ -18,4 +18,21 @@ impl ProcKernelInfo {
             eprocessVirtAddr: virtAddr,
         }
     }
+
+    /// DirectoryTableBase, the DTB the kernel runs the process with.
+    pub fn kernel_dtb(&self) -> u64 {
+        self.eprocess.Pcb.DirectoryTableBase
+    }
+
+    /// UserDirectoryTableBase, the shadow DTB user mode runs with under KVA shadow
+    /// (KPTI). `None` without KVA shadow, and for processes it is disabled for, which
+    /// leave it at 0 or 1.
+    pub fn user_dtb(&self) -> Option<u64> {
+        let user = self.eprocess.Pcb.UserDirectoryTableBase;
+        if user & !0xfff == 0 || user & !0xfff == self.kernel_dtb() & !0xfff {
+            None
+        } else {
+            Some(user)
+        }
+    }
 }
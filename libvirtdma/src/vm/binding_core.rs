@@ -1,42 +1,44 @@
-use crate::vm::{VMBinding, KFIXC, KFIXO};
-
-#[inline(always)]
-fn kfix2(x: u64) -> u64 {
-    if x < KFIXC {
-        x
-    } else {
-        x - KFIXO
-    }
-}
+use crate::error::{Error, Result};
+use crate::vm::{AccessMode, VMBinding};
+use std::sync::atomic::Ordering;
 
 impl VMBinding {
     pub(crate) fn memread(&self, local_addr: u64, remote_addr: u64, len: u64) -> bool {
-        let remote: u64 = kfix2(remote_addr);
-        unsafe {
-            if remote >= self.process.maps_size - len {
-                return false;
-            }
-            libc::memcpy(
-                local_addr as *mut libc::c_void,
-                (remote + self.process.maps_start) as *mut libc::c_void,
-                len as libc::size_t,
-            );
+        let local = unsafe { std::slice::from_raw_parts_mut(local_addr as *mut u8, len as usize) };
+        self.mem.phys_read(remote_addr, local)
+    }
+
+    pub(crate) fn memwrite(&self, local_addr: u64, remote_addr: u64, len: u64) -> Result<()> {
+        if self.access_mode() == AccessMode::ReadOnly {
+            return Err(Error::ReadOnly {
+                address: remote_addr,
+                len,
+            });
+        }
+        let local = unsafe { std::slice::from_raw_parts(local_addr as *const u8, len as usize) };
+        // The write may have hit a page table
+        self.tlb.lock().unwrap().invalidate_write(remote_addr, len);
+        if self.mem.phys_write(remote_addr, local) {
+            Ok(())
+        } else {
+            Err(Error::Write {
+                address: remote_addr,
+                len,
+            })
         }
-        return true;
     }
 
-    pub(crate) fn memwrite(&self, local_addr: u64, remote_addr: u64, len: u64) -> bool {
-        let remote: u64 = kfix2(remote_addr);
-        unsafe {
-            if remote >= self.process.maps_size - len {
-                return false;
-            }
-            libc::memcpy(
-                (remote + self.process.maps_start) as *mut libc::c_void,
-                local_addr as *mut libc::c_void,
-                len as libc::size_t,
-            );
+    pub fn access_mode(&self) -> AccessMode {
+        if self.writable.load(Ordering::SeqCst) {
+            AccessMode::ReadWrite
+        } else {
+            AccessMode::ReadOnly
         }
-        return true;
+    }
+
+    /// Allows or forbids writes to the guest from now on, for every holder of this binding.
+    pub fn set_access_mode(&self, mode: AccessMode) {
+        self.writable
+            .store(mode == AccessMode::ReadWrite, Ordering::SeqCst);
     }
 }
@@ -0,0 +1,21 @@
+use crate::backend::qmp::FreezeGuard;
+use crate::error::{Error, Result};
+use crate::vm::VMBinding;
+
+impl VMBinding {
+    /// Stops the guest's vCPUs through its QMP socket until the returned guard is dropped,
+    /// so multi-step walks (process lists, thread lists) see a consistent snapshot.
+    /// Translations cached while the guest was running are dropped once it's paused.
+    pub fn freeze(&self) -> Result<FreezeGuard> {
+        match &self.monitor {
+            Some(socket) => {
+                let guard = FreezeGuard::new(socket)?;
+                self.flush_tlb();
+                Ok(guard)
+            }
+            None => Err(Error::Qmp(
+                "no QMP socket known for this VM, unable to freeze it".to_string(),
+            )),
+        }
+    }
+}
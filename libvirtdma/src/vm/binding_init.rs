@@ -1,178 +1,82 @@
-use crate::vm::vmread_bind;
-use crate::vm::{NtHeaders, ProcessData, VMBinding, WinExport, WinProc};
+use crate::backend::crashdump::CrashDump;
+use crate::backend::elfcore::ElfCore;
+use crate::backend::guests::VmSelector;
+use crate::backend::kvm::GuestRam;
+use crate::backend::raw::RawImage;
+use crate::backend::vmware::VmwareSnapshot;
+use crate::backend::{open_guest_ram, open_image, open_live, LiveAccess, PhysicalMemory};
+use crate::error::{Error, Result};
+use crate::vm::paging::PagingMode;
+use crate::vm::tlb::{Tlb, DEFAULT_TTL};
+use crate::vm::{AccessMode, NtHeaders, VMBinding, WinExport, WinProc};
+use crate::win::eprocess::KPROCESS_USER_DIRECTORY_TABLE_BASE_OFFSET;
+use crate::win::ethread::KldrDataTableEntry;
+use crate::win::list_entry::ListEntry;
 use crate::win::Offsets;
 use byteorder::ByteOrder;
-use itertools::Itertools;
-use nix::fcntl::open;
-use nix::unistd::close;
 use pelite::image::{
     IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_NT_HEADERS32, IMAGE_NT_OPTIONAL_HDR32_MAGIC,
     IMAGE_NT_OPTIONAL_HDR64_MAGIC,
 };
 use pelite::pe64::image::{IMAGE_NT_HEADERS, IMAGE_NT_HEADERS_SIGNATURE};
-use proc_maps::{MapRange, Pid};
-use regex::bytes::Regex;
 use std::collections::HashMap;
-use std::io::Read;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::Duration;
 
 impl VMBinding {
-    pub fn new() -> Option<VMBinding> {
-        let mut binding = VMBinding {
-            offsets: None,
-            cached_nt_exports: HashMap::new(),
-            nt_kernel_entry: 0,
-            nt_kernel_modulebase: 0,
-            nt_version: 0,
-            nt_build: 0,
-            process: match Self::create_process_data() {
-                None => return None,
-                Some(s) => s,
-            },
-            initial_process: WinProc {
-                eprocess_va: 0,
-                eprocess_addr: 0,
-                dirbase: 0,
-                pid: 0,
-                name: "".to_string(),
-            },
-        };
-        if !binding.init_device() {
-            return None;
-        }
-        let (pml4, kernel_entry) = match binding.find_initial_process() {
-            Some(s) => s,
-            None => return None,
-        };
+    /// Binds to the running qemu-kvm guest through the `/proc/vmread` kernel module,
+    /// falling back to `process_vm_readv` and `/proc/<pid>/mem` if it isn't loaded.
+    pub fn new() -> Result<VMBinding> {
+        Self::attach(None, None)
+    }
 
-        binding.initial_process.dirbase = pml4;
-        println!("PML4: 0x{:x}", pml4);
+    /// Binds to the running qemu-kvm guest using only the given access method.
+    pub fn new_live(access: LiveAccess) -> Result<VMBinding> {
+        Self::attach(None, Some(access))
+    }
 
-        binding.nt_kernel_entry = kernel_entry;
-        println!("Kernel EntryPoint: 0x{:x}", kernel_entry);
+    /// Binds to the running guest picked by `vm` (pid, `-name` or `-uuid`), with the
+    /// given access method or the default fallback chain.
+    pub fn attach(vm: Option<&VmSelector>, access: Option<LiveAccess>) -> Result<VMBinding> {
+        Self::with_memory(open_live(vm, access)?)
+    }
 
-        match binding.find_nt_kernel(kernel_entry) {
-            Some((ntk, kexports)) => {
-                binding.nt_kernel_modulebase = ntk;
-                println!("NTKernel ModuleBase: 0x{:x}", ntk);
+    /// Binds to a flat guest RAM image captured earlier, no live VM required.
+    pub fn from_raw_image<P: AsRef<Path>>(path: P) -> Result<VMBinding> {
+        Self::with_memory(Box::new(RawImage::open(path)?))
+    }
 
-                // Less than ideal but we do it once. Better than having optionals or mutexes everywhere
-                for (k, v) in kexports.iter() {
-                    binding.cached_nt_exports.insert(k.clone(), v.clone());
-                }
-            }
-            None => {
-                // Test in case we are running XP (QEMU AddressSpace is different)
-                //   KFIXC = 0x40000000ll * 4;
-                //   KFIXO = 0x40000000;
-                //   FindNTKernel(ctx, kernelEntry);
-                return None;
-            }
-        };
+    /// Binds to an ELF core from QEMU's `dump-guest-memory` or VirtualBox's `dumpvmcore`.
+    pub fn from_elf_core<P: AsRef<Path>>(path: P) -> Result<VMBinding> {
+        Self::with_memory(Box::new(ElfCore::open(path)?))
+    }
 
-        let init_proc_addr = match binding.find_kernel_export("PsInitialSystemProcess") {
-            Some(0) | None => return None,
-            Some(addr) => addr,
-        };
-        binding.initial_process.eprocess_va =
-            binding.vread(binding.initial_process.dirbase, init_proc_addr);
-        binding.initial_process.eprocess_addr = binding.native_translate(
-            binding.initial_process.dirbase,
-            binding.initial_process.eprocess_va,
-        );
+    /// Binds to a 64-bit Windows kernel crash dump, taking the kernel DTB and
+    /// PsLoadedModuleList from its header.
+    pub fn from_crash_dump<P: AsRef<Path>>(path: P) -> Result<VMBinding> {
+        Self::with_memory(Box::new(CrashDump::open(path)?))
+    }
 
-        binding.nt_version = binding.get_nt_version();
-        binding.nt_build = binding.get_nt_build();
-        binding.offsets = Offsets::get_offsets(binding.nt_version, binding.nt_build);
-        if binding.offsets.is_none() {
-            return None;
-        };
+    /// Binds to a VMware `.vmem` file, using the region table from its `.vmss`/`.vmsn` state file.
+    pub fn from_vmware<P: AsRef<Path>, Q: AsRef<Path>>(vmem: P, meta: Q) -> Result<VMBinding> {
+        Self::with_memory(Box::new(VmwareSnapshot::open(vmem, meta)?))
+    }
 
-        Some(binding)
+    /// Binds to a memory image of any supported format, detected from the file contents.
+    pub fn from_image<P: AsRef<Path>>(path: P) -> Result<VMBinding> {
+        Self::with_memory(open_image(path)?)
     }
 
-    fn find_largest_kvm_maps() -> Option<Vec<MapRange>> {
-        match Self::find_kvm_user_pid() {
-            Some(pid) => {
-                let pmaps = match proc_maps::get_process_maps(pid as Pid) {
-                    Ok(pm) => pm,
-                    Err(e) => {
-                        println!("Unable to list process maps of qemu-kvm: {}", e.to_string());
-                        return None;
-                    }
-                };
-                Some(
-                    pmaps
-                        .iter()
-                        .cloned()
-                        .sorted_by(|a, b| Ord::cmp(&b.size(), &a.size()))
-                        .collect_vec(),
-                )
-            }
-            None => {
-                println!("Unable to find KVM PID");
-                None
-            }
-        }
+    /// Starts a binding with individual discovery steps overridden.
+    pub fn builder() -> VMBindingBuilder {
+        VMBindingBuilder::new()
     }
 
-    fn find_kvm_user_pid() -> Option<u64> {
-        let mut lsof_output: String = String::new();
-        match std::process::Command::new("lsof")
-            .arg("-Fp")
-            .arg("/dev/kvm")
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()
-        {
-            Err(e) => {
-                println!("Failed to run lsof to find qemu-kvm pid: {}", e.to_string());
-                return None;
-            }
-            Ok(child) => {
-                match child.wait_with_output() {
-                    Err(e) => {
-                        println!("Failed to run lsof to find qemu-kvm pid: {}", e.to_string());
-                        return None;
-                    }
-                    Ok(output) => match output.stdout.as_slice().read_to_string(&mut lsof_output) {
-                        Err(e) => {
-                            println!("Failed to read from lsof stdout while determining qemu-kvm pid: {}", e.to_string());
-                            return None;
-                        }
-                        Ok(readlen) => {
-                            if readlen == 0 || lsof_output.is_empty() {
-                                println!("lsof yielded empty stdout when determining qemu-kvm pid");
-                                return None;
-                            }
-                        }
-                    },
-                }
-            }
-        }
-        let re = Regex::new(r"p(?P<pid>\d+)\n").unwrap();
-        match re.captures(lsof_output.as_bytes()) {
-            None => {
-                println!("No captures in lsof output when determining qemu-kvm pid");
-                None
-            }
-            Some(captures) => {
-                let valid_caps: Vec<&[u8]> = captures
-                    .iter()
-                    .filter(|cap| cap.is_some())
-                    .map(|cap| cap.unwrap().as_bytes())
-                    .collect();
-                if valid_caps.len() != 2 {
-                    None
-                } else {
-                    let st: String = std::str::from_utf8(valid_caps.get(1).unwrap())
-                        .unwrap()
-                        .to_string();
-                    Result::ok(st.parse::<u64>())
-                }
-            }
-        }
+    /// Runs kernel discovery against an arbitrary guest-physical memory backend.
+    pub fn with_memory(mem: Box<dyn PhysicalMemory>) -> Result<VMBinding> {
+        VMBindingBuilder::new().memory(mem).build()
     }
 
     fn get_nt_version(&self) -> u16 {
@@ -265,11 +169,7 @@ impl VMBinding {
                                     .get_module_exports(self.initial_process.dirbase, nt_kernel)
                                 {
                                     Err(e) => {
-                                        println!(
-                                            "Failed to get module exports for the kernel at 0x{:x}: {}",
-                                            nt_kernel,
-                                            e,
-                                        );
+                                        debug!("Skipping kernel candidate: {}", e);
                                         continue;
                                     }
                                     Ok(kexports) => {
@@ -288,23 +188,53 @@ impl VMBinding {
         return None;
     }
 
-    pub fn get_nt_header(&self, dirbase: u64, address: u64) -> Option<(NtHeaders, u64)> {
+    // ntoskrnl.exe at a known base, its entry point taken from the PE header
+    fn nt_kernel_at(&self, base: u64) -> Result<(u64, u64, HashMap<String, WinExport>)> {
+        let entry = match self.get_nt_header(self.initial_process.dirbase, base)? {
+            (NtHeaders::Bit64(h), _) => base + h.OptionalHeader.AddressOfEntryPoint as u64,
+            (NtHeaders::Bit32(h), _) => base + h.OptionalHeader.AddressOfEntryPoint as u64,
+        };
+        let kexports = self.get_module_exports(self.initial_process.dirbase, base)?;
+        Ok((base, entry, kexports))
+    }
+
+    // The first entry of PsLoadedModuleList is always ntoskrnl.exe itself
+    fn find_nt_kernel_from_module_list(
+        &self,
+        module_list: u64,
+    ) -> Result<(u64, u64, HashMap<String, WinExport>)> {
+        let head: ListEntry = self.vread(self.initial_process.dirbase, module_list);
+        let ntoskrnl: KldrDataTableEntry = match head.get_next_from_kernel_initial_process(self, 0)
+        {
+            Some(m) => m,
+            None => {
+                return Err(Error::Bind(format!(
+                    "PsLoadedModuleList at 0x{:x} is empty",
+                    module_list
+                )));
+            }
+        };
+        let kexports = self.get_module_exports(self.initial_process.dirbase, ntoskrnl.DllBase)?;
+        Ok((ntoskrnl.DllBase, ntoskrnl.EntryPoint, kexports))
+    }
+
+    pub fn get_nt_header(&self, dirbase: u64, address: u64) -> Result<(NtHeaders, u64)> {
         let dos_header: IMAGE_DOS_HEADER = self.vread(dirbase, address);
         if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
-            return None;
+            return Err(Error::pe(address, "no MZ signature"));
         }
 
         let nt_header_addr = address + dos_header.e_lfanew as u64;
         let nt_header: IMAGE_NT_HEADERS = self.vread(dirbase, nt_header_addr);
         if nt_header.Signature != IMAGE_NT_HEADERS_SIGNATURE {
-            return None;
+            return Err(Error::pe(address, "no PE signature"));
         }
 
         let magic = nt_header.OptionalHeader.Magic;
         if magic == IMAGE_NT_OPTIONAL_HDR64_MAGIC {
-            Some((NtHeaders::Bit64(nt_header), nt_header_addr))
+            Ok((NtHeaders::Bit64(nt_header), nt_header_addr))
         } else if magic == IMAGE_NT_OPTIONAL_HDR32_MAGIC {
-            Some((
+            Ok((
                 NtHeaders::Bit32({
                     let nth: IMAGE_NT_HEADERS32 = self.vread(dirbase, nt_header_addr);
                     nth
@@ -312,12 +242,15 @@ impl VMBinding {
                 nt_header_addr,
             ))
         } else {
-            None
+            Err(Error::pe(
+                address,
+                format!("unknown optional header magic 0x{:x}", magic),
+            ))
         }
     }
 
-    // CheckLowStub -- It contains PML4 (kernel DirectoryTableBase) and Kernel EP.
-    fn find_initial_process(&self) -> Option<(u64, u64)> {
+    // CheckLowStub -- It contains PML4 (kernel DirectoryTableBase), Kernel EP and CR4.
+    fn find_initial_process(&self) -> Option<(u64, u64, u64)> {
         for i in 0..10 {
             let buf: [u8; 0x10000] = self.read(i * 0x10000);
             let mut o: usize = 0;
@@ -346,58 +279,277 @@ impl VMBinding {
                 // Page Map Level 4
                 let pml4 = xa0_ulonglong;
                 let kernel_entry = x70_ulonglong;
-                return Some((pml4, kernel_entry));
+                // The saved special registers go CR0, CR2, CR3, CR4
+                let cr4 = byteorder::LittleEndian::read_u64(&buf[o + 0xa8..]);
+                return Some((pml4, kernel_entry, cr4));
             }
         }
         return None;
     }
+}
+
+/// Binds with some of the discovery steps replaced by known values, for guests the
+/// heuristics in `VMBinding::new()` don't cover. Anything not supplied is discovered
+/// as usual: memory from the running qemu-kvm guest, the kernel DTB and ntoskrnl
+/// from the backend's hints or the low stub scan, the NT version/build from
+/// `RtlGetVersion`, and the offsets from the version table.
+#[derive(Default)]
+pub struct VMBindingBuilder {
+    mem: Option<Box<dyn PhysicalMemory>>,
+    vm: Option<VmSelector>,
+    ram: Option<GuestRam>,
+    access: Option<LiveAccess>,
+    monitor: Option<PathBuf>,
+    dtb: Option<u64>,
+    kernel_base: Option<u64>,
+    nt_version: Option<u16>,
+    nt_build: Option<u32>,
+    offsets: Option<Offsets>,
+    mode: Option<AccessMode>,
+    tlb_ttl: Option<Option<Duration>>,
+    paging: Option<PagingMode>,
+}
+
+impl VMBindingBuilder {
+    pub fn new() -> VMBindingBuilder {
+        VMBindingBuilder::default()
+    }
+
+    /// Uses this backend as is; the live VM options below are ignored.
+    pub fn memory(mut self, mem: Box<dyn PhysicalMemory>) -> VMBindingBuilder {
+        self.mem = Some(mem);
+        self
+    }
+
+    /// Attaches to the qemu-kvm process with this pid.
+    pub fn qemu_pid(mut self, pid: u64) -> VMBindingBuilder {
+        self.vm = Some(VmSelector::Pid(pid));
+        self
+    }
+
+    /// Attaches to the running guest picked by pid, `-name` or `-uuid`.
+    pub fn vm(mut self, vm: VmSelector) -> VMBindingBuilder {
+        self.vm = Some(vm);
+        self
+    }
+
+    /// Skips locating guest RAM inside qemu and uses these mappings and regions instead.
+    pub fn ram(mut self, ram: GuestRam) -> VMBindingBuilder {
+        self.ram = Some(ram);
+        self
+    }
+
+    pub fn access(mut self, access: LiveAccess) -> VMBindingBuilder {
+        self.access = Some(access);
+        self
+    }
+
+    /// QMP socket for `freeze()`, overriding whatever the backend found.
+    pub fn monitor<P: AsRef<Path>>(mut self, socket: P) -> VMBindingBuilder {
+        self.monitor = Some(socket.as_ref().to_path_buf());
+        self
+    }
+
+    /// Kernel DirectoryTableBase, skipping the low stub scan.
+    pub fn dtb(mut self, dtb: u64) -> VMBindingBuilder {
+        self.dtb = Some(dtb);
+        self
+    }
+
+    /// Virtual address of ntoskrnl.exe, skipping the kernel scan.
+    pub fn kernel_base(mut self, base: u64) -> VMBindingBuilder {
+        self.kernel_base = Some(base);
+        self
+    }
 
-    fn create_process_data() -> Option<ProcessData> {
-        let pid = match Self::find_kvm_user_pid() {
-            Some(p) => p,
-            None => return None,
+    /// NT version as `major * 100 + minor` (e.g. 1000 for Windows 10).
+    pub fn nt_version(mut self, version: u16) -> VMBindingBuilder {
+        self.nt_version = Some(version);
+        self
+    }
+
+    pub fn nt_build(mut self, build: u32) -> VMBindingBuilder {
+        self.nt_build = Some(build);
+        self
+    }
+
+    /// Structure offsets to use instead of looking them up by version and build.
+    pub fn offsets(mut self, offsets: Offsets) -> VMBindingBuilder {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Read-only bindings refuse every write to the guest. Defaults to `ReadWrite`.
+    pub fn access_mode(mut self, mode: AccessMode) -> VMBindingBuilder {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// How long cached address translations are trusted. Unless set, `tlb::DEFAULT_TTL`
+    /// for live backends and for good (`None`) for memory images.
+    /// `None` keeps them until `flush_tlb()`, which suits memory images.
+    pub fn tlb_ttl(mut self, ttl: Option<Duration>) -> VMBindingBuilder {
+        self.tlb_ttl = Some(ttl);
+        self
+    }
+
+    /// Page table format of the guest. Detected from the CR4 saved in the low stub, or
+    /// else guessed from the DTB, unless set; 32-bit guests need it set.
+    pub fn paging_mode(mut self, mode: PagingMode) -> VMBindingBuilder {
+        self.paging = Some(mode);
+        self
+    }
+
+    pub fn build(self) -> Result<VMBinding> {
+        let mem = match (self.mem, self.ram) {
+            (Some(mem), _) => mem,
+            (None, Some(ram)) => open_guest_ram(ram, self.access)?,
+            (None, None) => open_live(self.vm.as_ref(), self.access)?,
         };
-        let maps = match Self::find_largest_kvm_maps() {
-            Some(m) => m,
-            None => return None,
+        let monitor = match self.monitor {
+            Some(m) => Some(m),
+            None => mem.monitor_socket(),
         };
-        let largest_map = match maps.first() {
-            None => return None,
-            Some(l) => l,
+        let hints = mem.kernel_hints();
+        let tlb_ttl = match self.tlb_ttl {
+            Some(ttl) => ttl,
+            None if mem.is_live() => Some(DEFAULT_TTL),
+            None => None,
         };
-        return Some(ProcessData {
-            maps_start: largest_map.start() as u64,
-            maps_size: largest_map.size() as u64,
-            pid: pid as i32,
-        });
-    }
-
-    fn init_device(&mut self) -> bool {
-        let fd: i32 = match open(
-            "/proc/vmread",
-            nix::fcntl::OFlag::O_RDWR,
-            nix::sys::stat::Mode::S_IRWXO,
-        ) {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Failed to open /proc/vmread");
-                return false;
+        let mut binding = VMBinding {
+            offsets: None,
+            cached_nt_exports: HashMap::new(),
+            nt_kernel_entry: 0,
+            nt_kernel_modulebase: 0,
+            nt_version: 0,
+            nt_build: 0,
+            mem,
+            monitor,
+            writable: AtomicBool::new(self.mode != Some(AccessMode::ReadOnly)),
+            patches: Default::default(),
+            tlb: Mutex::new(Tlb::new(tlb_ttl)),
+            paging: PagingMode::default(),
+            shadow_dtbs: Default::default(),
+            write_processes: Default::default(),
+            kva_shadow: false,
+            initial_process: WinProc {
+                eprocess_va: 0,
+                eprocess_addr: 0,
+                dirbase: 0,
+                pid: 0,
+                name: "".to_string(),
+            },
+        };
+
+        // The low stub gives both the DTB and the kernel entry, the latter being
+        // where the ntoskrnl scan starts
+        let low_stub = match (self.dtb.or(hints.map(|h| h.dtb)), self.kernel_base, hints) {
+            (Some(_), Some(_), _) | (Some(_), None, Some(_)) => None,
+            _ => match binding.find_initial_process() {
+                Some(s) => Some(s),
+                None => {
+                    return Err(Error::Bind(
+                        "no low stub with the kernel DTB in the first 640KiB".to_string(),
+                    ));
+                }
+            },
+        };
+        binding.initial_process.dirbase = match (self.dtb, hints, low_stub) {
+            (Some(dtb), _, _) => {
+                info!("PML4 (supplied): 0x{:x}", dtb);
+                dtb
+            }
+            (None, Some(h), _) => {
+                info!("PML4 (from backend): 0x{:x}", h.dtb);
+                h.dtb
+            }
+            (None, None, Some((pml4, _, _))) => {
+                info!("PML4: 0x{:x}", pml4);
+                pml4
             }
+            (None, None, None) => unreachable!(),
         };
-        let res = match unsafe { vmread_bind(fd, &mut self.process) } {
-            Ok(res) => res == 0,
-            Err(e) => {
-                println!("Failed to call vmread ioctl: {}", e.to_string());
-                false
+        binding.paging = match (self.paging, low_stub) {
+            (Some(mode), _) => mode,
+            (None, Some((_, _, cr4))) => PagingMode::from_cr4(cr4),
+            (None, None) => PagingMode::from_dtb(binding.initial_process.dirbase),
+        };
+        info!("Paging: {:?}", binding.paging);
+
+        let (ntk, kernel_entry, kexports) = match (self.kernel_base, hints, low_stub) {
+            (Some(base), _, _) => binding.nt_kernel_at(base)?,
+            (None, Some(h), _) => {
+                binding.find_nt_kernel_from_module_list(h.ps_loaded_module_list)?
+            }
+            (None, None, Some((_, kernel_entry, _))) => {
+                match binding.find_nt_kernel(kernel_entry) {
+                    Some((ntk, kexports)) => (ntk, kernel_entry, kexports),
+                    None => {
+                        // Test in case we are running XP (QEMU AddressSpace is different)
+                        //   KFIXC = 0x40000000ll * 4;
+                        //   KFIXO = 0x40000000;
+                        //   FindNTKernel(ctx, kernelEntry);
+                        return Err(Error::Bind(format!(
+                            "ntoskrnl.exe not found around the kernel entry 0x{:x}",
+                            kernel_entry
+                        )));
+                    }
+                }
+            }
+            (None, None, None) => unreachable!(),
+        };
+
+        binding.nt_kernel_entry = kernel_entry;
+        info!("Kernel EntryPoint: 0x{:x}", kernel_entry);
+
+        binding.nt_kernel_modulebase = ntk;
+        info!("NTKernel ModuleBase: 0x{:x}", ntk);
+
+        // Less than ideal but we do it once. Better than having optionals or mutexes everywhere
+        for (k, v) in kexports.iter() {
+            binding.cached_nt_exports.insert(k.clone(), v.clone());
+        }
+
+        let init_proc_addr = match binding.find_kernel_export("PsInitialSystemProcess") {
+            Some(0) | None => {
+                return Err(Error::MissingExport("PsInitialSystemProcess".to_string()));
             }
+            Some(addr) => addr,
         };
-        match close(fd) {
-            Ok(()) => {}
-            Err(e) => println!(
-                "Error while closing the file descriptor to /proc/vmread: {}",
-                e.to_string()
-            ),
+        binding.initial_process.eprocess_va =
+            binding.vread(binding.initial_process.dirbase, init_proc_addr);
+        binding.initial_process.eprocess_addr = binding.native_translate(
+            binding.initial_process.dirbase,
+            binding.initial_process.eprocess_va,
+        );
+
+        binding.nt_version = match self.nt_version {
+            Some(v) => v,
+            None => binding.get_nt_version(),
+        };
+        binding.nt_build = match self.nt_build {
+            Some(b) => b,
+            None => binding.get_nt_build(),
+        };
+        binding.offsets = match self.offsets {
+            Some(o) => Some(o),
+            None => Some(Offsets::get_offsets(binding.nt_version, binding.nt_build)?),
         };
-        return res;
+
+        // KiKvaShadow isn't exported, but System gets a user DTB of its own exactly when
+        // the kernel runs with KVA shadow
+        binding.kva_shadow = match binding.paging {
+            PagingMode::FourLevel | PagingMode::FiveLevel => {
+                let user: u64 = binding.try_read(
+                    binding.initial_process.eprocess_addr
+                        + KPROCESS_USER_DIRECTORY_TABLE_BASE_OFFSET,
+                )?;
+                user & !0xfff != 0 && user & !0xfff != binding.initial_process.dirbase & !0xfff
+            }
+            _ => false,
+        };
+        info!("KVA shadow: {}", binding.kva_shadow);
+
+        Ok(binding)
     }
 }
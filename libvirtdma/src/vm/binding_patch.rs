@@ -0,0 +1,289 @@
+use crate::error::{Error, Result};
+use crate::proc_kernelinfo::ProcKernelInfo;
+use crate::vm::patchfile::ModulePatch;
+use crate::vm::{VMBinding, PAGE_OFFSET_SIZE};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// One write to guest memory made through `VMBinding::patch`, with what it replaced.
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub id: u64,
+    pub dirbase: u64,
+    /// Virtual address of the first patched byte
+    pub address: u64,
+    /// The guest-physical ranges written, in order; one per page the patch touches
+    pub physical: Vec<Range<u64>>,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+impl Patch {
+    fn overlaps(&self, other: &Patch) -> bool {
+        self.physical.iter().any(|a| {
+            other
+                .physical
+                .iter()
+                .any(|b| a.start < b.end && b.start < a.end)
+        })
+    }
+}
+
+/// Patches still applied to the guest, oldest first.
+#[derive(Debug, Default)]
+pub struct PatchJournal {
+    next_id: u64,
+    applied: Vec<Patch>,
+}
+
+impl VMBinding {
+    /// Writes `payload` at `address` in the address space of `dirbase` like `vwrite`, but
+    /// saves the bytes it replaces first so `unpatch` can put them back. Nothing is
+    /// written unless every page is mapped, and, without `force`, private to this
+    /// process. Returns the id of the patch.
+    pub fn patch(&self, dirbase: u64, address: u64, payload: &[u8], force: bool) -> Result<u64> {
+        if !force {
+            let processes = self.write_processes()?;
+            self.check_unshared(dirbase, &[(address, payload.len() as u64)], &processes)?;
+        }
+        let mut journal = self.patches.lock().unwrap();
+        let page_size = 1u64 << PAGE_OFFSET_SIZE;
+        let len = payload.len() as u64;
+        let mut physical = Vec::new();
+        let mut cursor: u64 = 0;
+        while cursor < len {
+            let va = address + cursor;
+            let chunk = std::cmp::min(page_size - (va & (page_size - 1)), len - cursor);
+            let pa = self.try_translate(dirbase, va)?;
+            physical.push(pa..pa + chunk);
+            cursor += chunk;
+        }
+
+        let mut original = vec![0u8; payload.len()];
+        let mut offset = 0usize;
+        for range in physical.iter() {
+            let chunk = (range.end - range.start) as usize;
+            self.try_read_into(range.start, &mut original[offset..offset + chunk])?;
+            offset += chunk;
+        }
+
+        let mut offset = 0usize;
+        for (i, range) in physical.iter().enumerate() {
+            let chunk = (range.end - range.start) as usize;
+            if let Err(e) = self.write(range.start, &payload[offset..offset + chunk]) {
+                // Don't leave half a patch behind
+                self.restore(&physical[..i], &original)?;
+                return Err(e);
+            }
+            offset += chunk;
+        }
+
+        let id = journal.next_id;
+        journal.next_id += 1;
+        journal.applied.push(Patch {
+            id,
+            dirbase,
+            address,
+            physical,
+            original,
+            patched: payload.to_vec(),
+        });
+        Ok(id)
+    }
+
+    /// The patches made through this binding that haven't been reverted, oldest first.
+    pub fn patches(&self) -> Vec<Patch> {
+        self.patches.lock().unwrap().applied.clone()
+    }
+
+    /// Puts back the bytes patch `id` replaced. Refused while a later patch overlaps it,
+    /// since that one saved the bytes of this patch as its original.
+    pub fn unpatch(&self, id: u64) -> Result<()> {
+        let mut journal = self.patches.lock().unwrap();
+        let idx = match journal.applied.iter().position(|p| p.id == id) {
+            Some(i) => i,
+            None => return Err(Error::UnknownPatch(id)),
+        };
+        let patch = &journal.applied[idx];
+        if let Some(later) = journal.applied[idx + 1..]
+            .iter()
+            .find(|p| p.overlaps(patch))
+        {
+            return Err(Error::PatchOverlap {
+                id,
+                later: later.id,
+            });
+        }
+        self.restore(&patch.physical, &patch.original)?;
+        journal.applied.remove(idx);
+        Ok(())
+    }
+
+    /// Reverts every outstanding patch, newest first. Returns how many were reverted.
+    pub fn unpatch_all(&self) -> Result<usize> {
+        let mut journal = self.patches.lock().unwrap();
+        let mut reverted = 0;
+        while let Some(patch) = journal.applied.last() {
+            self.restore(&patch.physical, &patch.original)?;
+            journal.applied.pop();
+            reverted += 1;
+        }
+        Ok(reverted)
+    }
+
+    /// The net effect of the outstanding patches in the process of `info`, relative to
+    /// the modules they fall in, ready for `patchfile::format_1337`. Bytes outside of
+    /// every module are left out with a warning.
+    pub fn export_patches(&self, info: &ProcKernelInfo) -> Vec<ModulePatch> {
+        let dirbase = info.eprocess.Pcb.DirectoryTableBase;
+        // Oldest original and newest value of every byte, in address order
+        let mut bytes: BTreeMap<u64, (u8, u8)> = BTreeMap::new();
+        for p in self.patches().iter().filter(|p| p.dirbase == dirbase) {
+            for (i, (old, new)) in p.original.iter().zip(p.patched.iter()).enumerate() {
+                bytes.entry(p.address + i as u64).or_insert((*old, *new)).1 = *new;
+            }
+        }
+
+        let modules = self.get_process_modules_map(info);
+        let mut out: Vec<ModulePatch> = Vec::new();
+        for (va, (old, new)) in bytes {
+            if old == new {
+                continue;
+            }
+            let (name, module) = match modules
+                .iter()
+                .find(|(_, m)| m.BaseAddress <= va && va < m.BaseAddress + m.SizeOfImage as u64)
+            {
+                Some(m) => m,
+                None => {
+                    warn!(
+                        "Patched byte at 0x{:x} is outside of every module, not exported",
+                        va
+                    );
+                    continue;
+                }
+            };
+            let rva = va - module.BaseAddress;
+            match out.last_mut() {
+                Some(last)
+                    if &last.module == name && last.rva + last.patched.len() as u64 == rva =>
+                {
+                    last.original.push(old);
+                    last.patched.push(new);
+                }
+                _ => out.push(ModulePatch {
+                    module: name.clone(),
+                    rva,
+                    original: vec![old],
+                    patched: vec![new],
+                }),
+            }
+        }
+        out
+    }
+
+    /// Applies module-relative patches (from `patchfile::parse_1337`) to the process of
+    /// `info`, relocated to where its modules are loaded. Everything is checked first:
+    /// nothing is written unless every module is loaded and every original byte matches.
+    /// Pages shared with other processes need `force`, as for `patch`. Returns the
+    /// journal ids of the new patches.
+    pub fn import_patches(
+        &self,
+        info: &ProcKernelInfo,
+        patches: &[ModulePatch],
+        force: bool,
+    ) -> Result<Vec<u64>> {
+        let dirbase = info.eprocess.Pcb.DirectoryTableBase;
+        let modules = self.get_process_modules_map(info);
+        let mut resolved = Vec::new();
+        for p in patches.iter() {
+            let base = match modules
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&p.module))
+            {
+                Some((_, m)) => m.BaseAddress,
+                None => return Err(Error::MissingModule(p.module.clone())),
+            };
+            let mut current = vec![0u8; p.original.len()];
+            self.try_vread_into(dirbase, base + p.rva, &mut current)?;
+            if current != p.original {
+                return Err(Error::PatchMismatch {
+                    module: p.module.clone(),
+                    rva: p.rva,
+                });
+            }
+            resolved.push((base + p.rva, &p.patched));
+        }
+        if !force {
+            let ranges: Vec<(u64, u64)> = resolved
+                .iter()
+                .map(|(address, bytes)| (*address, bytes.len() as u64))
+                .collect();
+            self.check_unshared(dirbase, &ranges, &self.write_processes()?)?;
+        }
+        let mut ids = Vec::new();
+        for (address, bytes) in resolved {
+            ids.push(self.patch(dirbase, address, bytes, true)?);
+        }
+        Ok(ids)
+    }
+
+    fn restore(&self, physical: &[Range<u64>], original: &[u8]) -> Result<()> {
+        let mut offset = 0usize;
+        for range in physical.iter() {
+            let chunk = (range.end - range.start) as usize;
+            self.write(range.start, &original[offset..offset + chunk])?;
+            offset += chunk;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_patch_journal() {
+    use crate::vm::binding_rw::flat_test_binding;
+    use crate::vm::AccessMode;
+
+    // VA 0x1000 is paged out behind a prototype PTE
+    let vm = flat_test_binding(&[(0x4008, (0xffff_8000_1234_5670u64 << 16) | 0x400)]);
+    vm.set_access_mode(AccessMode::ReadOnly);
+    assert!(matches!(
+        vm.patch(0x1000, 0x10, &[0xaa], false),
+        Err(Error::ReadOnly { .. })
+    ));
+    vm.set_access_mode(AccessMode::ReadWrite);
+
+    // The second page isn't mapped, so nothing may be written to the first one either
+    assert!(vm.patch(0x1000, 0xffe, &[1, 2, 3, 4], false).is_err());
+    assert_eq!(vm.try_read::<u16>(0x5ffe).unwrap(), 0);
+    assert!(vm.patches().is_empty());
+    let mut before = vec![0u8; 0x6000];
+    vm.try_read_into(0, &mut before).unwrap();
+    for force in [false, true].iter() {
+        assert!(matches!(
+            vm.patch(0x1000, 0x1000, &[0x90], *force),
+            Err(Error::Translation { .. })
+        ));
+    }
+    let mut after = vec![0u8; 0x6000];
+    vm.try_read_into(0, &mut after).unwrap();
+    assert!(before == after);
+    assert!(vm.patches().is_empty());
+
+    let first = vm.patch(0x1000, 0x10, &[0xaa, 0xbb], false).unwrap();
+    let second = vm.patch(0x1000, 0x11, &[0xcc], false).unwrap();
+    assert_eq!(vm.try_vread::<[u8; 2]>(0x1000, 0x10).unwrap(), [0xaa, 0xcc]);
+    let patches = vm.patches();
+    assert_eq!(patches[0].physical, vec![0x5010..0x5012]);
+    assert_eq!(patches[1].original, vec![0xbb]);
+
+    assert!(matches!(
+        vm.unpatch(first),
+        Err(Error::PatchOverlap { later, .. }) if later == second
+    ));
+    vm.unpatch(second).unwrap();
+    assert_eq!(vm.try_vread::<[u8; 2]>(0x1000, 0x10).unwrap(), [0xaa, 0xbb]);
+    assert!(matches!(vm.unpatch(second), Err(Error::UnknownPatch(_))));
+    assert_eq!(vm.unpatch_all().unwrap(), 1);
+    assert_eq!(vm.try_vread::<[u8; 2]>(0x1000, 0x10).unwrap(), [0, 0]);
+}
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use crate::error::{Error, Result};
 use crate::proc_kernelinfo::ProcKernelInfo;
 use crate::vm::VMBinding;
 use crate::win::eprocess::{PsProtectedSigner, PsProtectedType};
@@ -17,14 +18,8 @@ use term_table::table_cell::{Alignment, TableCell};
 use term_table::{Table, TableStyle};
 
 impl VMBinding {
-    pub fn find_kmod(&self, name: &str) -> Option<KldrDataTableEntry> {
-        match self.get_kmods() {
-            Err(e) => {
-                println!("Failed to get kernel modules: {}", e);
-                None
-            }
-            Ok(kmods) => kmods.get(name).cloned(),
-        }
+    pub fn find_kmod(&self, name: &str) -> Result<Option<KldrDataTableEntry>> {
+        Ok(self.get_kmods()?.get(name).cloned())
     }
 
     pub fn find_process_by_pid(&self, pid: u64, require_alive: bool) -> Option<ProcKernelInfo> {
@@ -36,6 +31,16 @@ impl VMBinding {
         return None;
     }
 
+    /// Like `find_process_by_pid`, telling a pid that isn't listed (`Ok(None)`) from a
+    /// process list that couldn't be read.
+    pub fn try_find_process_by_pid(
+        &self,
+        pid: u64,
+        require_alive: bool,
+    ) -> Result<Option<ProcKernelInfo>> {
+        Ok(self.try_get_processes(require_alive)?.remove(&pid))
+    }
+
     pub fn find_process_by_name(&self, name: &str, require_alive: bool) -> Option<ProcKernelInfo> {
         for (_, info) in self.get_processes(require_alive).iter() {
             if name.to_string().eq(&info.name) {
@@ -45,9 +50,9 @@ impl VMBinding {
         return None;
     }
 
-    pub fn list_kmods(&self) {
+    pub fn list_kmods(&self) -> Result<()> {
         match self.get_kmods() {
-            Err(e) => println!("Failed to list kernel modules: {}", e),
+            Err(e) => Err(e),
             Ok(kmods) => {
                 let mut table = Table::new();
                 table.max_column_width = 45;
@@ -79,16 +84,17 @@ impl VMBinding {
                     ]));
                 }
                 println!("{}", table.render());
+                Ok(())
             }
         }
     }
 
-    pub fn get_kmods(&self) -> Result<HashMap<String, KldrDataTableEntry>, &'static str> {
+    pub fn get_kmods(&self) -> Result<HashMap<String, KldrDataTableEntry>> {
         let kernel_dirbase = self.initial_process.dirbase;
         let module_list = match self.find_kernel_export("PsLoadedModuleList") {
             Some(addr) => addr,
             None => {
-                return Err("Failed to find the 'PsLoadedModuleList' kernel export");
+                return Err(Error::MissingExport("PsLoadedModuleList".to_string()));
             }
         };
 
@@ -119,7 +125,7 @@ impl VMBinding {
         proc: &mut ProcKernelInfo,
         typ: PsProtectedType,
         signer: PsProtectedSigner,
-    ) {
+    ) -> Result<()> {
         let current = &mut proc.eprocess.Protection;
         if current.SignerEnum() != signer {
             current.set_Signer(signer as u8);
@@ -128,7 +134,7 @@ impl VMBinding {
             current.set_Type(typ as u8);
         }
         // PS_PROTECTION offset in EPROCESS
-        self.write(proc.eprocessPhysAddr + 0x6ca, &current.value);
+        self.write(proc.eprocessPhysAddr + 0x6ca, &current.value)
     }
 
     pub fn list_processes(&self, require_alive: bool) {
@@ -145,6 +151,9 @@ impl VMBinding {
             TableCell::new_with_alignment("PID", 1, Alignment::Center),
             TableCell::new_with_alignment("Name", 1, Alignment::Center),
             TableCell::new_with_alignment("DirectoryTableBase", 1, Alignment::Center),
+            TableCell::builder("UserDirectoryTableBase")
+                .alignment(Alignment::Center)
+                .build(),
             TableCell::new_with_alignment("ProtectionType", 1, Alignment::Center),
             TableCell::new_with_alignment("Audit", 1, Alignment::Center),
             TableCell::new_with_alignment("Signer", 1, Alignment::Center),
@@ -159,6 +168,12 @@ impl VMBinding {
                     1,
                     Alignment::Center,
                 ),
+                TableCell::builder(match self.shadow_dtb(info.kernel_dtb()) {
+                    Some(user) => format!("0x{:x}", user),
+                    None => "-".to_string(),
+                })
+                .alignment(Alignment::Center)
+                .build(),
                 TableCell::new_with_alignment(
                     format!("{:?}", sprotect.TypeEnum()),
                     1,
@@ -187,12 +202,12 @@ impl VMBinding {
         )
     }
 
-    pub fn dump_module_vmem(&self, proc: &ProcKernelInfo, module: &LdrModule) -> Option<Box<[u8]>> {
+    pub fn dump_module_vmem(&self, proc: &ProcKernelInfo, module: &LdrModule) -> Result<Box<[u8]>> {
         match self.get_module_sections(proc, module).iter().last() {
-            None => None,
+            None => Err(Error::pe(module.BaseAddress, "no section headers")),
             Some(last_section) => {
                 let size = (last_section.VirtualAddress + last_section.SizeOfRawData) as u64;
-                Some(self.vreadvec(
+                Ok(self.vreadvec(
                     proc.eprocess.Pcb.DirectoryTableBase,
                     module.BaseAddress,
                     size,
@@ -210,20 +225,17 @@ impl VMBinding {
         let dos_header: IMAGE_DOS_HEADER = self.vread(dirbase, module.BaseAddress);
 
         if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
-            println!("WARN: unexpected e_magic (0x{:x})", dos_header.e_magic);
+            warn!("unexpected e_magic (0x{:x})", dos_header.e_magic);
         }
 
         let nt_header_addr = module.BaseAddress + dos_header.e_lfanew as u64;
         let new_exec_header: ImageNtHeaders64 = self.vread(dirbase, nt_header_addr);
         if new_exec_header.Signature != IMAGE_NT_HEADERS_SIGNATURE {
-            println!(
-                "WARN: unexpected NTHeader (0x{:x})",
-                new_exec_header.Signature
-            );
+            warn!("unexpected NTHeader (0x{:x})", new_exec_header.Signature);
         }
         if new_exec_header.OptionalHeader.Magic != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
-            println!(
-                "WARN: unexpected Optional64Hdr (0x{:x})",
+            warn!(
+                "unexpected Optional64Hdr (0x{:x})",
                 new_exec_header.OptionalHeader.Magic
             );
         }
@@ -242,7 +254,7 @@ impl VMBinding {
     pub fn list_process_modules(&self, proc: &mut ProcKernelInfo) {
         let modules = self.get_process_modules(proc);
         if modules.is_empty() {
-            println!("Unable to find any process modules");
+            warn!("Unable to find any process modules");
             return;
         }
         let mut table = Table::new();
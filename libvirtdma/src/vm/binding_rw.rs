@@ -1,6 +1,11 @@
-use crate::vm::{VMBinding, PAGE_OFFSET_SIZE, PMASK};
+use crate::error::{Error, Result};
+use crate::pod::{self, Pod};
+use crate::proc_kernelinfo::ProcKernelInfo;
+use crate::vm::{VMBinding, PAGE_OFFSET_SIZE};
+use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::mem::{size_of, MaybeUninit};
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 macro_rules! min {
@@ -69,44 +74,190 @@ impl VMBinding {
         unsafe { ret.assume_init() }
     }
 
+    /// Reads a `T` from guest-physical memory, failing if any part of it isn't backed.
+    pub fn try_read<T: Pod>(&self, address: u64) -> Result<T> {
+        let mut ret: T = pod::zeroed();
+        self.try_read_into(address, pod::bytes_of_mut(&mut ret))?;
+        Ok(ret)
+    }
+
+    /// Fills `buf` from guest-physical memory at `address`. If only a prefix could be read,
+    /// the error is a `PartialRead` telling how long that prefix is; `buf` holds it.
+    pub fn try_read_into(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len() as u64;
+        if self.memread(buf.as_mut_ptr() as u64, address, len) {
+            return Ok(());
+        }
+        // Go again page by page to find out where it stops
+        let mut cursor: u64 = 0;
+        while cursor < len {
+            let pa = address + cursor;
+            let read = min!(STEP_SIZE - (pa & (STEP_SIZE - 1)), len - cursor);
+            let local = buf[cursor as usize..].as_mut_ptr() as u64;
+            if !self.memread(local, pa, read) {
+                let source = Error::Read {
+                    address: pa,
+                    len: read,
+                };
+                return Err(Error::partial_read(address, len, cursor, source));
+            }
+            cursor += read;
+        }
+        Ok(())
+    }
+
+    /// Reads a `T` from the address space of `dirbase`, failing on unmapped pages.
+    pub fn try_vread<T: Pod>(&self, dirbase: u64, address: u64) -> Result<T> {
+        let mut ret: T = pod::zeroed();
+        self.try_vread_into(dirbase, address, pod::bytes_of_mut(&mut ret))?;
+        Ok(ret)
+    }
+
+    /// Fills `buf` from the address space of `dirbase`, page by page. Stops at the first
+    /// page that isn't mapped or readable, reporting how much was read before it.
+    pub fn try_vread_into(&self, dirbase: u64, address: u64, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len() as u64;
+        let mut cursor: u64 = 0;
+        while cursor < len {
+            let va = address + cursor;
+            let read = min!(STEP_SIZE - (va & (STEP_SIZE - 1)), len - cursor);
+            let chunk = &mut buf[cursor as usize..(cursor + read) as usize];
+            if let Err(source) = self
+                .try_translate(dirbase, va)
+                .and_then(|pa| self.try_read_into(pa, chunk))
+            {
+                return Err(Error::partial_read(address, len, cursor, source));
+            }
+            cursor += read;
+        }
+        Ok(())
+    }
+
+    /// Translates `address` in the address space of `dirbase` to a guest-physical address,
+    /// or 0 if it isn't mapped.
     pub fn native_translate(&self, dirbase: u64, address: u64) -> u64 {
-        let dir_base = dirbase & !0xfu64;
-        let page_offset = address & !(!0u64 << PAGE_OFFSET_SIZE);
-        let pte = (address >> PAGE_OFFSET_SIZE) & 0x1ffu64;
-        let pt = (address >> 21) & 0x1ffu64;
-        let pd = (address >> 30) & 0x1ffu64;
-        let pdp = (address >> 39) & 0x1ffu64;
+        self.try_translate(dirbase, address).unwrap_or(0)
+    }
 
-        let pdpe: u64 = self.read(dir_base + 8 * pdp);
-        if !pdpe & 1u64 != 0 {
-            return 0;
+    /// Like `native_translate`, but tells an unmapped address (`Translation`) apart from
+    /// paging structures that can't be read (`Read`). Under KVA shadow, user-mode
+    /// addresses are looked up with the process's user DTB first and with `dirbase`
+    /// if the page doesn't translate there. A process's user DTB is only known once
+    /// `get_processes` has listed it; until then `dirbase` alone is used.
+    pub fn try_translate(&self, dirbase: u64, address: u64) -> Result<u64> {
+        if address < self.paging.user_space_end() {
+            if let Some(user) = self.shadow_dtb(dirbase) {
+                if let Ok(pa) = self.cached_translate(user, address) {
+                    return Ok(pa);
+                }
+            }
         }
+        self.cached_translate(dirbase, address)
+    }
 
-        let pde: u64 = self.read((pdpe & PMASK) + 8 * pd);
-        if !pde & 1u64 != 0 {
-            return 0;
+    /// The KVA shadow user DTB registered for the kernel DTB `dirbase`, if any.
+    pub(crate) fn shadow_dtb(&self, dirbase: u64) -> Option<u64> {
+        let shadow_dtbs = self.shadow_dtbs.lock().unwrap();
+        shadow_dtbs.get(&self.paging.table_base(dirbase)).copied()
+    }
+
+    fn cached_translate(&self, dirbase: u64, address: u64) -> Result<u64> {
+        let dtb = self.paging.table_base(dirbase);
+        if let Some(pa) = self.tlb.lock().unwrap().lookup(dtb, address) {
+            return Ok(pa);
         }
+        let mut tables = Vec::new();
+        let (pa, shift) = self.walk_page_tables(dirbase, address, &mut tables)?;
+        self.tlb
+            .lock()
+            .unwrap()
+            .insert(dtb, address, pa, shift, &tables);
+        Ok(pa)
+    }
 
-        // 1GB large page, use pde's 12-34 bits
-        if pde & 0x80u64 != 0 {
-            return (pde & (!0u64 << 42 >> PAGE_OFFSET_SIZE)) + (address & !(!0u64 << 30));
+    /// Drops every cached translation, e.g. after the guest was resumed for a while,
+    /// along with the process list unforced writes check against.
+    pub fn flush_tlb(&self) {
+        self.tlb.lock().unwrap().flush();
+        *self.write_processes.lock().unwrap() = None;
+    }
+
+    /// The processes `check_unshared` compares against on unforced writes, listed again
+    /// once the TLB's TTL has passed rather than on every write.
+    pub(crate) fn write_processes(&self) -> Result<HashMap<u64, ProcKernelInfo>> {
+        let ttl = self.tlb.lock().unwrap().ttl();
+        let mut cached = self.write_processes.lock().unwrap();
+        if let Some((listed, processes)) = cached.as_ref() {
+            if ttl.is_none_or(|ttl| listed.elapsed() < ttl) {
+                return Ok(processes.clone());
+            }
         }
+        let processes = self.try_get_processes(false)?;
+        *cached = Some((Instant::now(), processes.clone()));
+        Ok(processes)
+    }
 
-        let pte_addr: u64 = self.read((pde & PMASK) + 8 * pt);
-        if !pte_addr & 1u64 != 0 {
-            return 0;
+    /// Drops the cached translations of the address space of `dirbase`, and of its
+    /// KVA shadow.
+    pub fn flush_tlb_dtb(&self, dirbase: u64) {
+        let mut tlb = self.tlb.lock().unwrap();
+        tlb.flush_dtb(self.paging.table_base(dirbase));
+        if let Some(user) = self.shadow_dtb(dirbase) {
+            tlb.flush_dtb(self.paging.table_base(user));
         }
+    }
+
+    /// How long cached translations are trusted; `None` keeps them until flushed.
+    pub fn set_tlb_ttl(&self, ttl: Option<Duration>) {
+        self.tlb.lock().unwrap().set_ttl(ttl);
+    }
+
+    /// Translation cache hits and misses so far.
+    pub fn tlb_stats(&self) -> (u64, u64) {
+        let tlb = self.tlb.lock().unwrap();
+        (tlb.hits, tlb.misses)
+    }
 
-        // 2MB large page
-        if pte_addr & 0x80u64 != 0 {
-            return (pte_addr & PMASK) + (address & !(!0u64 << 21));
+    /// The guest-physical address of `address` and the size (as a shift) of the page
+    /// mapping it, read from the page tables in the binding's paging mode. The address of
+    /// every paging structure read on the way is added to `tables`.
+    fn walk_page_tables(
+        &self,
+        dirbase: u64,
+        address: u64,
+        tables: &mut Vec<u64>,
+    ) -> Result<(u64, u64)> {
+        let not_mapped = Error::Translation { dirbase, address };
+        let mode = self.paging;
+        let mut table = mode.table_base(dirbase);
+        for level in (2..=mode.levels()).rev() {
+            tables.push(table);
+            let entry =
+                self.read_table_entry(table + mode.entry_size() * mode.index(address, level))?;
+            if !entry & 1u64 != 0 {
+                return Err(not_mapped);
+            }
+            if mode.large_page(level, entry) {
+                let shift = mode.shift(level);
+                let offset_mask = !(!0u64 << shift);
+                return Ok((
+                    (entry & mode.frame_mask() & !offset_mask) + (address & offset_mask),
+                    shift,
+                ));
+            }
+            table = entry & mode.frame_mask();
         }
 
-        let resolved_addr: u64 = self.read::<u64>((pte_addr & PMASK) + 8 * pte) & PMASK;
-        if resolved_addr == 0 {
-            return 0;
+        // Windows keeps page file offsets and prototype PTE addresses in the frame bits
+        // of non-present PTEs, so only a present one points to the page
+        tables.push(table);
+        let pte = self.read_table_entry(table + mode.entry_size() * mode.index(address, 1))?;
+        if !pte & 1u64 != 0 {
+            return Err(not_mapped);
         }
-        return resolved_addr + page_offset;
+        let resolved_addr = pte & mode.frame_mask();
+        let page_offset = address & !(!0u64 << PAGE_OFFSET_SIZE);
+        Ok((resolved_addr + page_offset, PAGE_OFFSET_SIZE))
     }
 
     pub fn read_cstring_from_physical_mem(&self, addr: u64, maxlen: Option<u64>) -> String {
@@ -128,37 +279,311 @@ impl VMBinding {
         std::string::String::from_iter(out.iter().map(|b| *b as char))
     }
 
-    pub fn vwrite(&self, dirbase: u64, address: u64, payload: &[u8]) {
-        self._vwrite(
-            dirbase,
-            address,
-            payload.as_ptr() as u64,
-            payload.len() as u64,
-        );
+    /// Writes `payload` at `address` in the address space of `dirbase`, page by page.
+    /// Stops at the first page that isn't mapped or can't be written. Unless `force` is
+    /// set, nothing is written if one of the pages is shared with other processes.
+    pub fn vwrite(&self, dirbase: u64, address: u64, payload: &[u8], force: bool) -> Result<()> {
+        if !force {
+            let processes = self.write_processes()?;
+            self.check_unshared(dirbase, &[(address, payload.len() as u64)], &processes)?;
+        }
+        let len = payload.len() as u64;
+        let mut cursor: u64 = 0;
+        while cursor < len {
+            let va = address + cursor;
+            let write = min!(STEP_SIZE - (va & (STEP_SIZE - 1)), len - cursor);
+            let pa = self.native_translate(dirbase, va);
+            if pa == 0 {
+                return Err(Error::Translation {
+                    dirbase,
+                    address: va,
+                });
+            }
+            self.write(pa, &payload[cursor as usize..(cursor + write) as usize])?;
+            cursor += write;
+        }
+        Ok(())
     }
 
-    fn _vwrite(&self, dirbase: u64, address: u64, localbuffer_begin: u64, len: u64) {
-        if address >> PAGE_OFFSET_SIZE == (address + len) >> PAGE_OFFSET_SIZE {
-            self.memwrite(
-                localbuffer_begin,
-                self.native_translate(dirbase, address),
-                len,
-            );
-        } else {
+    /// Other processes whose user half maps the physical page behind `address`, at any
+    /// virtual address, the way the sections of shared DLLs are. Kernel addresses,
+    /// mapped into every process anyway, have none.
+    pub fn page_sharers(&self, dirbase: u64, address: u64) -> Result<Vec<ProcKernelInfo>> {
+        let processes = self.try_get_processes(false)?;
+        let shared = self.shared_pages(dirbase, &[(address, 1)], &processes)?;
+        Ok(shared
+            .into_iter()
+            .next()
+            .map(|(_, sharers)| sharers)
+            .unwrap_or_default())
+    }
+
+    /// The pages of `ranges` (VA, length) in the user half of `dirbase` that other
+    /// processes among `processes` map too, each with those processes, in VA order.
+    /// Every other address space is walked once, whatever the number of pages.
+    pub(crate) fn shared_pages(
+        &self,
+        dirbase: u64,
+        ranges: &[(u64, u64)],
+        processes: &HashMap<u64, ProcKernelInfo>,
+    ) -> Result<Vec<(u64, Vec<ProcKernelInfo>)>> {
+        let user_space_end = self.paging.user_space_end();
+        // (VA, physical page) of every page in the ranges
+        let mut pages: Vec<(u64, u64)> = Vec::new();
+        for (address, len) in ranges.iter() {
             let mut cursor: u64 = 0;
-            while cursor < len {
-                let write = min!(STEP_SIZE, len - cursor);
-                self.memwrite(
-                    localbuffer_begin + cursor,
-                    self.native_translate(dirbase, address + cursor),
-                    write,
-                );
-                cursor += write;
+            while cursor < *len {
+                let va = address + cursor;
+                if va < user_space_end {
+                    let pa = self.try_translate(dirbase, va)?;
+                    pages.push((va, pa >> PAGE_OFFSET_SIZE << PAGE_OFFSET_SIZE));
+                }
+                cursor += min!(STEP_SIZE - (va & (STEP_SIZE - 1)), len - cursor);
+            }
+        }
+        if pages.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let own = self.paging.table_base(dirbase);
+        let spaces: Vec<(u64, String, u64, bool)> = processes
+            .values()
+            .filter(|info| self.paging.table_base(info.kernel_dtb()) != own)
+            .map(|info| {
+                let pid = info.eprocess.UniqueProcessId;
+                (pid, info.name.clone(), info.kernel_dtb(), false)
+            })
+            .collect();
+        let targets: Vec<u64> = pages.iter().map(|(_, page)| *page).collect();
+        let mappings = self.mappings_of(&targets, &spaces);
+
+        let mut shared = Vec::new();
+        for (va, page) in pages {
+            let mut sharers: Vec<ProcKernelInfo> = mappings
+                .iter()
+                .filter(|m| m.range.pa + (m.va - m.range.va.start) == page)
+                .filter_map(|m| processes.get(&m.pid).cloned())
+                .collect();
+            sharers.dedup_by_key(|info| info.eprocess.UniqueProcessId);
+            if !sharers.is_empty() {
+                shared.push((va, sharers));
             }
         }
+        Ok(shared)
+    }
+
+    /// Fails with `SharedPage` for the first page of `ranges` (VA, length) that other
+    /// processes among `processes` map too.
+    pub(crate) fn check_unshared(
+        &self,
+        dirbase: u64,
+        ranges: &[(u64, u64)],
+        processes: &HashMap<u64, ProcKernelInfo>,
+    ) -> Result<()> {
+        match self
+            .shared_pages(dirbase, ranges, processes)?
+            .into_iter()
+            .next()
+        {
+            Some((address, sharers)) => Err(Error::SharedPage {
+                address,
+                processes: sharers
+                    .iter()
+                    .map(|info| (info.eprocess.UniqueProcessId, info.name.clone()))
+                    .collect(),
+            }),
+            None => Ok(()),
+        }
     }
 
-    pub fn write(&self, address: u64, payload: &[u8]) -> bool {
+    /// Writes `payload` to guest-physical memory at `address`. Fails with `ReadOnly`
+    /// unless the binding is in `AccessMode::ReadWrite`.
+    pub fn write(&self, address: u64, payload: &[u8]) -> Result<()> {
         self.memwrite(payload.as_ptr() as u64, address, payload.len() as u64)
     }
 }
+
+/// 0x6000 bytes of guest RAM in a `Vec`, paged through PML4 0x1000 -> PDPT 0x2000 ->
+/// PD 0x3000 -> PT 0x4000 so that VA 0 (and nothing else) maps to 0x5000. `entries`
+/// are (physical address, u64) pairs written on top; the binding starts out writable.
+#[cfg(test)]
+pub(crate) fn flat_test_binding(entries: &[(u64, u64)]) -> VMBinding {
+    use crate::backend::PhysicalMemory;
+    use crate::vm::WinProc;
+    use std::collections::HashMap;
+    use std::ops::Range;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::RwLock;
+
+    struct FlatMemory(RwLock<Vec<u8>>);
+    impl PhysicalMemory for FlatMemory {
+        fn phys_read(&self, addr: u64, buf: &mut [u8]) -> bool {
+            match self
+                .0
+                .read()
+                .unwrap()
+                .get(addr as usize..addr as usize + buf.len())
+            {
+                Some(src) => {
+                    buf.copy_from_slice(src);
+                    true
+                }
+                None => false,
+            }
+        }
+        fn phys_write(&self, addr: u64, buf: &[u8]) -> bool {
+            let mut ram = self.0.write().unwrap();
+            match ram.get_mut(addr as usize..addr as usize + buf.len()) {
+                Some(dst) => {
+                    dst.copy_from_slice(buf);
+                    true
+                }
+                None => false,
+            }
+        }
+        fn size(&self) -> u64 {
+            self.0.read().unwrap().len() as u64
+        }
+        fn holes(&self) -> Vec<Range<u64>> {
+            Vec::new()
+        }
+    }
+
+    let mut ram = vec![0u8; 0x6000];
+    for (table, entry) in &[(0x1000, 0x2003u64), (0x2000, 0x3003), (0x3000, 0x4003)] {
+        ram[*table..*table + 8].copy_from_slice(&entry.to_le_bytes());
+    }
+    ram[0x4000..0x4008].copy_from_slice(&0x5003u64.to_le_bytes());
+    for (address, value) in entries {
+        ram[*address as usize..*address as usize + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    VMBinding {
+        nt_kernel_entry: 0,
+        nt_version: 0,
+        nt_build: 0,
+        nt_kernel_modulebase: 0,
+        initial_process: WinProc {
+            eprocess_va: 0,
+            eprocess_addr: 0,
+            dirbase: 0x1000,
+            pid: 4,
+            name: "System".to_string(),
+        },
+        cached_nt_exports: HashMap::new(),
+        mem: Box::new(FlatMemory(RwLock::new(ram))),
+        monitor: None,
+        offsets: None,
+        writable: AtomicBool::new(true),
+        patches: Default::default(),
+        tlb: Default::default(),
+        paging: Default::default(),
+        shadow_dtbs: Default::default(),
+        write_processes: Default::default(),
+        kva_shadow: false,
+    }
+}
+
+#[test]
+fn test_checked_access() {
+    use crate::vm::AccessMode;
+
+    let vm = flat_test_binding(&[(0x5ff8, 0x1122334455667788)]);
+    vm.set_access_mode(AccessMode::ReadOnly);
+
+    assert_eq!(
+        vm.try_vread::<u64>(0x1000, 0xff8).unwrap(),
+        0x1122334455667788
+    );
+    match vm.try_vread::<[u64; 2]>(0x1000, 0xff8) {
+        Err(Error::PartialRead { read, source, .. }) => {
+            assert_eq!(read, 8);
+            match *source {
+                Error::Translation { address, .. } => assert_eq!(address, 0x1000),
+                e => panic!("unexpected {}", e),
+            }
+        }
+        r => panic!("unexpected {:?}", r.map(|_| ())),
+    }
+    match vm.try_read::<u64>(0x5ffc) {
+        Err(Error::PartialRead { read, .. }) => assert_eq!(read, 4),
+        r => panic!("unexpected {:?}", r.map(|_| ())),
+    }
+    match vm.try_read::<u64>(0x6000) {
+        Err(Error::Read { address, len }) => assert_eq!((address, len), (0x6000, 8)),
+        r => panic!("unexpected {:?}", r.map(|_| ())),
+    }
+    assert!(vm.try_read::<u32>(0x5ffc).is_ok());
+
+    assert_eq!(vm.access_mode(), AccessMode::ReadOnly);
+    assert!(matches!(
+        vm.write(0x5000, &[0]),
+        Err(Error::ReadOnly { .. })
+    ));
+    vm.set_access_mode(AccessMode::ReadWrite);
+    assert!(vm.write(0x5000, &[0x88]).is_ok());
+    assert!(matches!(vm.write(0x6000, &[0]), Err(Error::Write { .. })));
+
+    // A prototype PTE and a page file PTE, both with bits set in the frame
+    vm.write(
+        0x4008,
+        &((0xffff_8000_1234_5670u64 << 16) | 0x400).to_le_bytes(),
+    )
+    .unwrap();
+    vm.write(0x4010, &((0x1234u64 << 32) | (4 << 5)).to_le_bytes())
+        .unwrap();
+    for va in [0x1000u64, 0x2000].iter() {
+        assert!(matches!(
+            vm.try_translate(0x1000, *va),
+            Err(Error::Translation { .. })
+        ));
+        assert!(vm.try_vread::<u8>(0x1000, *va).is_err());
+    }
+}
+
+#[test]
+fn test_shared_pages() {
+    // Another process, its PML4 at 0, maps 0x5000 at VA 0x8000000000 instead of 0
+    let vm = flat_test_binding(&[(0x8, 0x2003)]);
+    let mut other = ProcKernelInfo::new("other.exe", pod::zeroed(), 0, 0);
+    other.eprocess.UniqueProcessId = 8;
+    let processes: HashMap<u64, ProcKernelInfo> = vec![(8, other)].into_iter().collect();
+
+    match vm.check_unshared(0x1000, &[(0x10, 4)], &processes) {
+        Err(Error::SharedPage { address, processes }) => {
+            assert_eq!(address, 0x10);
+            assert_eq!(processes, vec![(8, "other.exe".to_string())]);
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    // Only other processes count, and kernel addresses are never shared
+    assert!(vm
+        .check_unshared(0, &[(0x80_0000_0010, 4)], &processes)
+        .is_ok());
+    assert!(vm
+        .check_unshared(0x1000, &[(0xffff_8000_0000_0000, 4)], &processes)
+        .is_ok());
+    assert!(matches!(
+        vm.check_unshared(0x1000, &[(0x1000, 1)], &processes),
+        Err(Error::Translation { .. })
+    ));
+
+    // `force` skips the check altogether
+    vm.vwrite(0x1000, 0x10, &[0xaa], true).unwrap();
+    let id = vm.patch(0x1000, 0x11, &[0xbb], true).unwrap();
+    assert_eq!(vm.try_read::<[u8; 2]>(0x5010).unwrap(), [0xaa, 0xbb]);
+    vm.unpatch(id).unwrap();
+
+    // Unforced writes check against the listed processes until the TLB is flushed
+    vm.set_tlb_ttl(None);
+    *vm.write_processes.lock().unwrap() = Some((Instant::now(), processes));
+    assert!(matches!(
+        vm.vwrite(0x1000, 0x10, &[0xaa], false),
+        Err(Error::SharedPage { .. })
+    ));
+    assert!(matches!(
+        vm.patch(0x1000, 0x10, &[0xaa], false),
+        Err(Error::SharedPage { .. })
+    ));
+    vm.flush_tlb();
+    assert!(vm.write_processes.lock().unwrap().is_none());
+}
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use crate::error::{Error, Result};
 use crate::vm::VMBinding;
 use memmem::Searcher;
 use regex::bytes::Regex;
@@ -16,18 +17,22 @@ impl VMBinding {
         memmem::TwoWaySearcher::new(needle).search_in(haystack)
     }
 
-    pub fn pmemmem(haystack: &[u8], needle_string: &str) -> Result<Vec<usize>, String> {
+    pub fn pmemmem(haystack: &[u8], needle_string: &str) -> Result<Vec<usize>> {
         let mut restr = String::from("(?-u:");
         for ch in str_chunks(&needle_string, 2) {
             let chunk: Vec<char> = ch.chars().collect();
             if chunk.len() != 2 {
-                return Err("input needle_string without even length".to_string());
+                return Err(Error::Parse(
+                    "input needle_string without even length".to_string(),
+                ));
             }
             let (first, second) = (*chunk.get(0).unwrap(), *chunk.get(1).unwrap());
             let qm_present = first == '?' || second == '?';
             let wildcard = first == '?' && second == '?';
             if qm_present && !wildcard {
-                return Err("needle_string has wildcards of uneven length".to_string());
+                return Err(Error::Parse(
+                    "needle_string has wildcards of uneven length".to_string(),
+                ));
             }
             if wildcard {
                 restr += ".";
@@ -40,7 +45,7 @@ impl VMBinding {
 
         let re: Regex = match Regex::new(&restr) {
             Ok(r) => r,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(Error::Parse(e.to_string())),
         };
         Ok(re.find_iter(haystack).map(|f| f.start()).collect())
     }
@@ -1,3 +1,4 @@
+use crate::error::{Error, Result};
 use byteorder::ByteOrder;
 use itertools::Itertools;
 
@@ -7,7 +8,7 @@ pub fn parse_u64(s: &str, le: bool) -> Option<u64> {
         let lh = parse_u64(&parts[0], le);
         let rh = parse_u64(&parts[1], le);
         if lh.is_none() || rh.is_none() {
-            println!("Invalid expression");
+            debug!("Invalid expression {}", s);
             return None;
         }
         return Some(lh.unwrap() + rh.unwrap());
@@ -68,7 +69,7 @@ pub struct MemoryLayout {
 }
 
 impl MemoryLayout {
-    pub fn from_x64dbg_table(s: &str) -> Result<MemoryLayout, &str> {
+    pub fn from_x64dbg_table(s: &str) -> Result<MemoryLayout> {
         let mut lines = s.lines();
         let mut sections: indexmap::IndexMap<u64, MemoryRange> = indexmap::IndexMap::new();
         let mut last_section_name = String::new();
@@ -79,15 +80,17 @@ impl MemoryLayout {
             let subsection = line.contains('"');
             let parts: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect_vec();
             if parts.len() < 5 {
-                return Err("came across a line with less than 5 parts");
+                return Err(Error::Parse(
+                    "came across a line with less than 5 parts".to_string(),
+                ));
             }
             let start: u64 = match parse_u64(&format!("0x{}", parts[0]), false) {
                 Some(r) => r,
-                None => return Err("cannot parse the start"),
+                None => return Err(Error::Parse("cannot parse the start".to_string())),
             };
             let size: u64 = match parse_u64(&format!("0x{}", parts[1]), false) {
                 Some(r) => r,
-                None => return Err("cannot parse the start"),
+                None => return Err(Error::Parse("cannot parse the size".to_string())),
             };
             let name = if parts.len() == 5 {
                 "".to_string()
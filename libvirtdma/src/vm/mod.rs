@@ -1,22 +1,39 @@
+use crate::backend::PhysicalMemory;
+use crate::proc_kernelinfo::ProcKernelInfo;
+use crate::vm::binding_patch::PatchJournal;
+use crate::vm::paging::PagingMode;
+use crate::vm::tlb::Tlb;
 use crate::win::Offsets;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::Instant;
 
 pub mod binding_core;
 
 pub mod binding_disasm;
 
+pub mod binding_freeze;
 pub mod binding_init;
+pub mod binding_patch;
 pub mod binding_porcelain;
 pub mod binding_rw;
 pub mod binding_search;
 pub mod nativebinding;
 
 pub mod mlayout;
+pub mod pagewalk;
+pub mod paging;
+pub mod patchfile;
+pub mod pte;
+pub mod tlb;
 
 const PAGE_OFFSET_SIZE: u64 = 12;
 const PMASK: u64 = (!0xfu64 << 8) & 0xfffffffffu64;
-const KFIXC: u64 = 0x80000000;
-const KFIXO: u64 = 0x80000000;
+/// Canonical user-mode addresses end here with 4-level paging, the kernel half starts at
+/// 0xffff800000000000. See `PagingMode::user_space_end` for the other modes.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
 
 const VMREAD_IOCTL_MAGIC: u8 = 0x42;
 
@@ -50,6 +67,13 @@ pub struct WinModule {
     pub load_count: u16,
 }
 
+/// Whether a binding may modify the guest. Checked on every write, whatever the backend allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    ReadOnly,
+    ReadWrite,
+}
+
 #[derive(Debug, Clone)]
 pub struct WinProc {
     pub eprocess_va: u64,
@@ -66,6 +90,27 @@ pub struct VMBinding {
     pub nt_kernel_modulebase: u64,
     pub initial_process: WinProc,
     pub cached_nt_exports: HashMap<String, WinExport>,
-    pub process: ProcessData,
+    pub mem: Box<dyn PhysicalMemory>,
+    /// QMP socket used by `freeze()`
+    pub monitor: Option<PathBuf>,
     pub offsets: Option<Offsets>,
+    /// `AccessMode::ReadWrite`, shared so the mode can be switched behind an `Arc`
+    pub(crate) writable: AtomicBool,
+    /// What `patch()` overwrote, for `unpatch()`
+    pub(crate) patches: Mutex<PatchJournal>,
+    /// Translations of `try_translate`, used by every virtual read and write
+    pub(crate) tlb: Mutex<Tlb>,
+    /// Page table format of the guest, fixed once bound
+    pub(crate) paging: PagingMode,
+    /// Kernel DTB -> KVA shadow user DTB of the processes `get_processes` came across
+    pub(crate) shadow_dtbs: Mutex<HashMap<u64, u64>>,
+    /// Processes unforced writes check for shared pages, and when they were listed.
+    /// Trusted as long as translations are.
+    pub(crate) write_processes: Mutex<Option<(Instant, HashMap<u64, ProcKernelInfo>)>>,
+    /// Whether the guest runs with KVA shadow, read from the System process at bind time
+    pub(crate) kva_shadow: bool,
 }
+
+// Bindings to different guests (or dumps) share nothing, so one process can hold
+// several and hand them to other threads
+static_assertions::assert_impl_all!(VMBinding: Send, Sync);
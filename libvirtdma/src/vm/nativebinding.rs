@@ -1,7 +1,9 @@
 #![allow(dead_code)]
+use crate::error::{Error, Result};
 use crate::proc_kernelinfo::ProcKernelInfo;
-use crate::vm::VMBinding;
+use crate::vm::paging::PagingMode;
 use crate::vm::WinExport;
+use crate::vm::{VMBinding, PMASK};
 use crate::win::eprocess::EPROCESS;
 use crate::win::ethread::{ETHREAD, KTHREAD_THREAD_LIST_OFFSET};
 use crate::win::heap_entry::HEAP;
@@ -20,9 +22,14 @@ impl VMBinding {
     }
 
     pub fn find_kernel_export(&self, name: &str) -> Option<u64> {
+        self.try_find_kernel_export(name).ok()
+    }
+
+    /// Address of the ntoskrnl export `name`, `Error::MissingExport` if there's none.
+    pub fn try_find_kernel_export(&self, name: &str) -> Result<u64> {
         match self.cached_nt_exports.get(name) {
-            None => None,
-            Some(export) => Some(export.address),
+            None => Err(Error::MissingExport(name.to_string())),
+            Some(export) => Ok(export.address),
         }
     }
 
@@ -30,13 +37,10 @@ impl VMBinding {
         &self,
         dirbase: u64,
         module_base: u64,
-    ) -> Result<HashMap<String, WinExport>, String> {
+    ) -> Result<HashMap<String, WinExport>> {
         let mut hmap = HashMap::new();
 
-        let nt_headers_addr = match self.get_nt_header(dirbase, module_base) {
-            Some((_, addr)) => addr,
-            _ => return Err("couldn't get the NT header".to_string()),
-        };
+        let (_, nt_headers_addr) = self.get_nt_header(dirbase, module_base)?;
 
         let data_dir_offset =
             size_of::<IMAGE_FILE_HEADER>() + size_of::<u32>() + size_of::<IMAGE_OPTIONAL_HEADER>()
@@ -44,29 +48,36 @@ impl VMBinding {
         let export_table: IMAGE_DATA_DIRECTORY =
             self.vread(dirbase, nt_headers_addr + data_dir_offset as u64);
         if export_table.Size > 0x7fffffu32 {
-            return Err(format!(
-                "table size of 0x{:x} is greater than 0x7fffff",
-                export_table.Size
+            return Err(Error::pe(
+                module_base,
+                format!(
+                    "table size of 0x{:x} is greater than 0x7fffff",
+                    export_table.Size
+                ),
             ));
         }
         if export_table.VirtualAddress as u64 == module_base {
-            return Err(format!(
-                "VirtualAddress of export_table equals the module_base 0x{:x}",
-                module_base
+            return Err(Error::pe(
+                module_base,
+                "VirtualAddress of export_table equals the module_base",
             ));
         }
         if export_table.Size < size_of::<IMAGE_EXPORT_DIRECTORY>() as u32 {
-            return Err(format!(
-                "ExportTable size ({:x}) is smaller than size of IMAGE_EXPORT_DIRECTORY",
-                export_table.Size,
+            return Err(Error::pe(
+                module_base,
+                format!(
+                    "ExportTable size ({:x}) is smaller than size of IMAGE_EXPORT_DIRECTORY",
+                    export_table.Size,
+                ),
             ));
         }
 
         let buf_begin = module_base + export_table.VirtualAddress as u64;
         let export_dir: IMAGE_EXPORT_DIRECTORY = self.vread(dirbase, buf_begin);
         if export_dir.NumberOfNames == 0 || export_dir.AddressOfNames == 0 {
-            return Err(format!(
-                "IMAGE_EXPORT_DIRECTORY->NumberOfNames or AddressOfNames is 0"
+            return Err(Error::pe(
+                module_base,
+                "IMAGE_EXPORT_DIRECTORY->NumberOfNames or AddressOfNames is 0",
             ));
         }
 
@@ -129,6 +140,14 @@ impl VMBinding {
         self.vread(dirbase, ptr)
     }
 
+    /// Like `get_full_peb`, failing if the PEB pointer or the PEB can't be read. Processes
+    /// without a PEB (System, Registry) have a null one, which doesn't translate.
+    pub fn try_get_full_peb(&self, dirbase: u64, phys_process: u64) -> Result<FullPEB> {
+        let peb_offset_from_eprocess = self.offsets.unwrap().peb as u64;
+        let ptr: u64 = self.try_read(phys_process + peb_offset_from_eprocess)?;
+        self.try_vread(dirbase, ptr)
+    }
+
     pub fn get_heaps_with_dirbase(&self, dirbase: u64, phys_process: u64) -> Vec<HEAP> {
         let peb = self.get_full_peb(dirbase, phys_process);
         // let primary_heap = peb.ProcessHeap;
@@ -145,12 +164,34 @@ impl VMBinding {
 
     pub fn get_processes(&self, require_alive: bool) -> HashMap<u64, ProcKernelInfo> {
         let mut m: HashMap<u64, ProcKernelInfo> = HashMap::new();
+        if let Err(e) = self.walk_processes(require_alive, &mut m) {
+            warn!("Returning early from walking EPROCESS list: {}", e);
+        }
+        return m;
+    }
+
+    /// Like `get_processes`, but fails if an entry or link of the EPROCESS list can't be
+    /// read rather than returning the processes found up to it. The PEB and name of each
+    /// process are still read leniently, so a paged-out PEB doesn't fail the walk.
+    pub fn try_get_processes(&self, require_alive: bool) -> Result<HashMap<u64, ProcKernelInfo>> {
+        let mut m: HashMap<u64, ProcKernelInfo> = HashMap::new();
+        self.walk_processes(require_alive, &mut m)?;
+        Ok(m)
+    }
+
+    /// Adds the processes of the EPROCESS list to `m`, stopping at the first entry or
+    /// link that can't be read.
+    fn walk_processes(
+        &self,
+        require_alive: bool,
+        m: &mut HashMap<u64, ProcKernelInfo>,
+    ) -> Result<()> {
         let mut cur_proc = self.initial_process.eprocess_addr;
         let mut virt_process = self.initial_process.eprocess_va;
         loop {
-            let eprocess: EPROCESS = self.read(cur_proc);
+            let eprocess: EPROCESS = self.try_read(cur_proc)?;
             if eprocess.UniqueProcessId == 0 {
-                println!("WARN: Returning early from walking EPROCESS because PID is 0");
+                warn!("Returning early from walking EPROCESS because PID is 0");
                 break;
             }
 
@@ -177,26 +218,21 @@ impl VMBinding {
                 // Liveness check
                 let pe_magic: [u8; 2] = self.vread(dirbase, peb.ImageBaseAddress);
                 let valid_pe = pe_magic[0] == 'M' as u8 && pe_magic[1] == 'Z' as u8;
+                let info = ProcKernelInfo::new(&base_module_name, eprocess, virt_process, cur_proc);
+                self.register_user_dtb(&info);
                 if valid_pe || !require_alive {
-                    m.insert(
-                        eprocess.UniqueProcessId,
-                        ProcKernelInfo::new(&base_module_name, eprocess, virt_process, cur_proc),
-                    );
+                    m.insert(eprocess.UniqueProcessId, info);
                 }
             }
 
             let apl_offset = self.offsets.unwrap().apl as u64;
-            let vp: u64 = self.read(cur_proc + apl_offset);
+            let vp: u64 = self.try_read(cur_proc + apl_offset)?;
             virt_process = vp - apl_offset;
             if virt_process == 0 {
-                println!("Returning early from walking EPROCESS list due to VIRTPROC == 0");
-                break;
-            }
-            cur_proc = self.native_translate(self.initial_process.dirbase, virt_process);
-            if cur_proc == 0 {
-                println!("Returning early from walking EPROCESS list due to CURPROC == 0");
+                warn!("Returning early from walking EPROCESS list due to VIRTPROC == 0");
                 break;
             }
+            cur_proc = self.try_translate(self.initial_process.dirbase, virt_process)?;
             if cur_proc == self.initial_process.eprocess_addr
                 || virt_process == self.initial_process.eprocess_va
             {
@@ -204,7 +240,39 @@ impl VMBinding {
                 break;
             }
         }
-        return m;
+        Ok(())
+    }
+
+    /// Whether the guest runs with KVA shadow (KPTI), as told by the user DTB of the
+    /// System process when the binding was made.
+    pub fn kva_shadow(&self) -> bool {
+        self.kva_shadow
+    }
+
+    /// Remembers the user DTB of `info` for `try_translate`, once it checks out: under
+    /// KVA shadow, the user half of both top level tables points to the same tables.
+    fn register_user_dtb(&self, info: &ProcKernelInfo) {
+        let user = match (info.user_dtb(), self.paging) {
+            (Some(user), PagingMode::FourLevel) | (Some(user), PagingMode::FiveLevel) => user,
+            _ => return,
+        };
+        if self.shadow_dtb(info.kernel_dtb()).is_some() {
+            return;
+        }
+        let kernel = self.paging.table_base(info.kernel_dtb());
+        let halves = (
+            self.try_read::<[u64; 256]>(kernel),
+            self.try_read::<[u64; 256]>(self.paging.table_base(user)),
+        );
+        if let (Ok(k), Ok(u)) = halves {
+            let shadowed = k.iter().any(|e| e & 1 != 0)
+                && k.iter()
+                    .zip(u.iter())
+                    .all(|(k, u)| k & 1 == 0 || k & PMASK == u & PMASK);
+            if shadowed {
+                self.shadow_dtbs.lock().unwrap().insert(kernel, user);
+            }
+        }
     }
 
     pub fn threads_from_eprocess(&self, info: &ProcKernelInfo) -> Vec<ETHREAD> {
@@ -233,3 +301,52 @@ impl VMBinding {
         return threads;
     }
 }
+
+#[test]
+fn test_kva_shadow() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    // A shadow PML4 at 0x5000 sharing the user half of the one at 0x1000, plus a
+    // mapping of its own at 0x8000000000
+    let vm = flat_test_binding(&[(0x5000, 0x2003), (0x5008, 0x2003), (0x5800, 0x2003)]);
+    let mut info = ProcKernelInfo::new("test.exe", crate::pod::zeroed(), 0, 0);
+    info.eprocess.Pcb.DirectoryTableBase = 0x1000;
+    info.eprocess.Pcb.UserDirectoryTableBase = 0x4000;
+    vm.register_user_dtb(&info);
+    assert_eq!(vm.shadow_dtb(0x1000), None);
+    info.eprocess.Pcb.UserDirectoryTableBase = 0x5000;
+    vm.register_user_dtb(&info);
+    assert_eq!(vm.shadow_dtb(0x1000), Some(0x5000));
+
+    assert_eq!(vm.try_translate(0x1000, 0x80_0000_0123).unwrap(), 0x5123);
+    // Not mapped through the shadow, found through the kernel DTB
+    vm.write(0x5000, &0u64.to_le_bytes()).unwrap();
+    vm.flush_tlb();
+    assert_eq!(vm.try_translate(0x1000, 0x123).unwrap(), 0x5123);
+    // PCID bits in a DTB name the same address space
+    vm.flush_tlb_dtb(0x1ab0);
+    let (hits, _) = vm.tlb_stats();
+    assert_eq!(vm.try_translate(0x1000, 0x123).unwrap(), 0x5123);
+    assert_eq!(vm.tlb_stats().0, hits);
+    // Kernel addresses only ever go through the kernel DTB
+    assert!(vm.try_translate(0x1000, 0xffff_8000_0000_0123).is_err());
+}
+
+#[test]
+fn test_try_lookups() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    let mut vm = flat_test_binding(&[]);
+    assert!(matches!(
+        vm.try_find_kernel_export("PsLoadedModuleList"),
+        Err(Error::MissingExport(_))
+    ));
+    assert_eq!(vm.find_kernel_export("PsLoadedModuleList"), None);
+
+    // The System EPROCESS runs past the end of guest RAM
+    vm.initial_process.eprocess_addr = 0x5f00;
+    assert!(vm.try_get_processes(false).is_err());
+    assert!(vm.get_processes(false).is_empty());
+    assert!(vm.try_find_process_by_pid(4, false).is_err());
+    assert!(vm.find_process_by_pid(4, false).is_none());
+}
@@ -0,0 +1,295 @@
+use crate::vm::paging::PagingMode;
+use crate::vm::VMBinding;
+use byteorder::{ByteOrder, LittleEndian};
+use std::ops::Range;
+
+/// Contiguous virtual memory, backed by contiguous guest-physical memory and mapped by
+/// pages of one size with the same permissions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedRange {
+    pub va: Range<u64>,
+    /// Guest-physical address of `va.start`
+    pub pa: u64,
+    /// 4K, 2M or 1G (4M with 32-bit non-PAE paging)
+    pub page_size: u64,
+    /// U/S set at every level
+    pub user: bool,
+    /// R/W set at every level
+    pub writable: bool,
+    /// NX set at any level
+    pub nx: bool,
+}
+
+impl MappedRange {
+    /// `u`/`k`, then `r`, `w` and `x` or `-`, like `urw-`
+    pub fn permissions(&self) -> String {
+        format!(
+            "{}r{}{}",
+            if self.user { 'u' } else { 'k' },
+            if self.writable { 'w' } else { '-' },
+            if self.nx { '-' } else { 'x' }
+        )
+    }
+
+    fn extend(&mut self, page: &MappedRange) -> bool {
+        let contiguous = self.va.end == page.va.start
+            && self.pa + (self.va.end - self.va.start) == page.pa
+            && self.page_size == page.page_size
+            && (self.user, self.writable, self.nx) == (page.user, page.writable, page.nx);
+        if contiguous {
+            self.va.end = page.va.end;
+        }
+        contiguous
+    }
+}
+
+/// A virtual address some process maps a given guest-physical address at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalMapping {
+    pub pid: u64,
+    pub name: String,
+    pub dirbase: u64,
+    pub va: u64,
+    /// The mapped range `va` is in, for its permissions and page size
+    pub range: MappedRange,
+}
+
+/// One paging structure on the way down and where the walk is in it.
+struct Table {
+    entries: Vec<u64>,
+    next: usize,
+    base_va: u64,
+    /// 1 for a PT up to `PagingMode::levels()` for the top level structure
+    level: u8,
+    user: bool,
+    writable: bool,
+    nx: bool,
+}
+
+/// Iterates over everything mapped in one address space in ascending VA order (the
+/// user half first), reading the paging structures of the binding's `PagingMode` as
+/// it goes. Paging structures that can't be read are skipped.
+pub struct PageWalk<'a> {
+    vm: &'a VMBinding,
+    stack: Vec<Table>,
+    pending: Option<MappedRange>,
+}
+
+impl<'a> PageWalk<'a> {
+    fn push_table(&mut self, pa: u64, base_va: u64, level: u8, parent: (bool, bool, bool)) {
+        let mode = self.vm.paging;
+        let size = mode.entry_size() as usize;
+        let mut raw = vec![0u8; mode.entries(level) as usize * size];
+        match self.vm.try_read_into(pa, &mut raw) {
+            Ok(()) => self.stack.push(Table {
+                entries: raw
+                    .chunks(size)
+                    .map(|e| match size {
+                        4 => LittleEndian::read_u32(e) as u64,
+                        _ => LittleEndian::read_u64(e),
+                    })
+                    .collect(),
+                next: 0,
+                base_va,
+                level,
+                user: parent.0,
+                writable: parent.1,
+                nx: parent.2,
+            }),
+            Err(e) => debug!("Skipping the paging structure at 0x{:x}: {}", pa, e),
+        }
+    }
+
+    /// The next present leaf entry as a single page.
+    fn next_page(&mut self) -> Option<MappedRange> {
+        let mode = self.vm.paging;
+        loop {
+            let table = self.stack.last_mut()?;
+            if table.next == table.entries.len() {
+                self.stack.pop();
+                continue;
+            }
+            let idx = table.next as u64;
+            table.next += 1;
+            let entry = table.entries[idx as usize];
+            if entry & 1 == 0 {
+                continue;
+            }
+            let level = table.level;
+            let va = if level == mode.levels() {
+                mode.top_level_va(idx)
+            } else {
+                table.base_va + (idx << mode.shift(level))
+            };
+            // PAE PDPTEs have no U/S or R/W bits
+            let pdpte = mode == PagingMode::Pae && level == 3;
+            let perms = (
+                table.user && (pdpte || entry & 0x4 != 0),
+                table.writable && (pdpte || entry & 0x2 != 0),
+                table.nx || entry & (1 << 63) != 0,
+            );
+            // PS makes a PDPTE or PDE a large page, a PTE always is a 4K one
+            if level == 1 || mode.large_page(level, entry) {
+                let page_size = 1u64 << mode.shift(level);
+                return Some(MappedRange {
+                    va: va..va.wrapping_add(page_size),
+                    pa: entry & mode.frame_mask() & !(page_size - 1),
+                    page_size,
+                    user: perms.0,
+                    writable: perms.1,
+                    nx: perms.2,
+                });
+            }
+            self.push_table(entry & mode.frame_mask(), va, level - 1, perms);
+        }
+    }
+}
+
+impl<'a> Iterator for PageWalk<'a> {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        let mut range = match self.pending.take() {
+            Some(r) => r,
+            None => self.next_page()?,
+        };
+        while let Some(page) = self.next_page() {
+            if !range.extend(&page) {
+                self.pending = Some(page);
+                break;
+            }
+        }
+        Some(range)
+    }
+}
+
+impl VMBinding {
+    /// Walks the page tables of `dirbase`, yielding every mapped range.
+    pub fn walk_address_space(&self, dirbase: u64) -> PageWalk<'_> {
+        let mut walk = PageWalk {
+            vm: self,
+            stack: Vec::new(),
+            pending: None,
+        };
+        let mode = self.paging;
+        walk.push_table(
+            mode.table_base(dirbase),
+            0,
+            mode.levels(),
+            (true, true, false),
+        );
+        walk
+    }
+
+    /// Every virtual address the guest-physical address `pa` is mapped at: in the user
+    /// half of each process from `get_processes`, and in the kernel half of the kernel
+    /// DTB only, since that one is mapped into every process. Sorted by pid, then VA.
+    pub fn reverse_translate(&self, pa: u64) -> Vec<PhysicalMapping> {
+        let kernel = &self.initial_process;
+        let mut spaces: Vec<(u64, String, u64, bool)> =
+            vec![(kernel.pid, kernel.name.clone(), kernel.dirbase, true)];
+        for info in self.get_processes(false).values() {
+            let dirbase = info.eprocess.Pcb.DirectoryTableBase;
+            if self.paging.table_base(dirbase) != self.paging.table_base(kernel.dirbase) {
+                spaces.push((
+                    info.eprocess.UniqueProcessId,
+                    info.name.clone(),
+                    dirbase,
+                    false,
+                ));
+            }
+        }
+        self.mappings_of(&[pa], &spaces)
+    }
+
+    /// Where each of the guest-physical addresses `targets` is mapped in `spaces` (pid,
+    /// name, dirbase and whether to walk the kernel half too), walking each address
+    /// space once. Sorted by pid, then VA.
+    pub(crate) fn mappings_of(
+        &self,
+        targets: &[u64],
+        spaces: &[(u64, String, u64, bool)],
+    ) -> Vec<PhysicalMapping> {
+        let user_space_end = self.paging.user_space_end();
+        let mut found = Vec::new();
+        for (pid, name, dirbase, kernel) in spaces.iter() {
+            let ranges = self
+                .walk_address_space(*dirbase)
+                .take_while(|r| *kernel || r.va.start < user_space_end);
+            for range in ranges {
+                let len = range.va.end.wrapping_sub(range.va.start);
+                for pa in targets
+                    .iter()
+                    .filter(|pa| range.pa <= **pa && **pa - range.pa < len)
+                {
+                    found.push(PhysicalMapping {
+                        pid: *pid,
+                        name: name.clone(),
+                        dirbase: *dirbase,
+                        va: range.va.start + (pa - range.pa),
+                        range: range.clone(),
+                    });
+                }
+            }
+        }
+        found.sort_by_key(|m| (m.pid, m.va));
+        found
+    }
+}
+
+#[test]
+fn test_walk_address_space() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    // Two read-only pages next to each other, an NX one and a 2M page
+    let vm = flat_test_binding(&[
+        (0x4008, 0x6001),
+        (0x4010, 0x7001),
+        (0x4018, 0x8000_0000_0000_9003),
+        (0x3008, 0x20_0083),
+    ]);
+
+    let ranges: Vec<MappedRange> = vm.walk_address_space(0x1000).collect();
+    let summary: Vec<(Range<u64>, u64, u64, String)> = ranges
+        .iter()
+        .map(|r| (r.va.clone(), r.pa, r.page_size, r.permissions()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (0..0x1000, 0x5000, 0x1000, "krwx".to_string()),
+            (0x1000..0x3000, 0x6000, 0x1000, "kr-x".to_string()),
+            (0x3000..0x4000, 0x9000, 0x1000, "krw-".to_string()),
+            (
+                0x20_0000..0x40_0000,
+                0x20_0000,
+                0x20_0000,
+                "krwx".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_reverse_translate() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    // 0x5000 aliased at VA 0x2000, and the whole hierarchy again in the kernel half
+    let vm = flat_test_binding(&[(0x4010, 0x8000_0000_0000_5001), (0x1800, 0x2003)]);
+
+    let found: Vec<(u64, u64, String)> = vm
+        .reverse_translate(0x5123)
+        .into_iter()
+        .map(|m| (m.pid, m.va, m.range.permissions()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (4, 0x123, "krwx".to_string()),
+            (4, 0x2123, "kr--".to_string()),
+            (4, 0xffff_8000_0000_0123, "krwx".to_string()),
+            (4, 0xffff_8000_0000_2123, "kr--".to_string()),
+        ]
+    );
+    assert!(vm.reverse_translate(0x4000).is_empty());
+}
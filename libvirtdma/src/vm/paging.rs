@@ -0,0 +1,177 @@
+use crate::error::Result;
+use crate::vm::{VMBinding, PAGE_OFFSET_SIZE, PMASK, USER_SPACE_END};
+
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+
+/// Page table format the guest uses, which decides how virtual addresses are translated.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PagingMode {
+    /// 32-bit without PAE: page directory and page table of 1024 4-byte entries, 4M large pages
+    TwoLevel,
+    /// 32-bit PAE: a 4-entry PDPT, then 512-entry PD and PT, 2M large pages
+    Pae,
+    /// Long mode: PML4, PDPT, PD and PT, 1G and 2M large pages
+    #[default]
+    FourLevel,
+    /// Long mode with LA57: a PML5 above the PML4, 57-bit virtual addresses
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// From the CR4 saved in the low stub, which only 64-bit kernels have.
+    /// Falls back to 4-level paging if the value can't be a long mode CR4.
+    pub fn from_cr4(cr4: u64) -> PagingMode {
+        if cr4 & CR4_PAE != 0 && cr4 & CR4_LA57 != 0 {
+            PagingMode::FiveLevel
+        } else {
+            PagingMode::FourLevel
+        }
+    }
+
+    /// Guessed from the DTB alone: a PAE CR3 only has to be 32-byte aligned and below
+    /// 4G, anything else is taken for a 4-level PML4.
+    pub fn from_dtb(dtb: u64) -> PagingMode {
+        if dtb < 1 << 32 && dtb & 0xfff != 0 && dtb & 0x1f == 0 {
+            PagingMode::Pae
+        } else {
+            PagingMode::FourLevel
+        }
+    }
+
+    /// Paging structures on the way from the DTB to a page.
+    pub fn levels(self) -> u8 {
+        match self {
+            PagingMode::TwoLevel => 2,
+            PagingMode::Pae => 3,
+            PagingMode::FourLevel => 4,
+            PagingMode::FiveLevel => 5,
+        }
+    }
+
+    /// Bytes per paging structure entry.
+    pub fn entry_size(self) -> u64 {
+        match self {
+            PagingMode::TwoLevel => 4,
+            _ => 8,
+        }
+    }
+
+    /// How many VA bits one entry of the structure at `level` (1 for the PT) covers.
+    pub fn shift(self, level: u8) -> u64 {
+        match self {
+            PagingMode::TwoLevel => PAGE_OFFSET_SIZE + 10 * (level as u64 - 1),
+            _ => PAGE_OFFSET_SIZE + 9 * (level as u64 - 1),
+        }
+    }
+
+    /// Number of entries in the structure at `level`.
+    pub fn entries(self, level: u8) -> u64 {
+        match (self, level) {
+            (PagingMode::TwoLevel, _) => 1024,
+            (PagingMode::Pae, 3) => 4,
+            _ => 512,
+        }
+    }
+
+    /// Index of the entry for `address` in the structure at `level`.
+    pub fn index(self, address: u64, level: u8) -> u64 {
+        (address >> self.shift(level)) & (self.entries(level) - 1)
+    }
+
+    /// Whether a present entry at `level` with PS set maps a page instead of a table.
+    pub fn large_page(self, level: u8, entry: u64) -> bool {
+        let allowed = match self {
+            PagingMode::TwoLevel | PagingMode::Pae => level == 2,
+            _ => level == 2 || level == 3,
+        };
+        allowed && entry & 0x80 != 0
+    }
+
+    /// Bits of an entry holding the physical address of the table or page.
+    pub fn frame_mask(self) -> u64 {
+        match self {
+            PagingMode::TwoLevel => 0xffff_f000,
+            _ => PMASK,
+        }
+    }
+
+    /// Physical address of the top level structure.
+    pub fn table_base(self, dirbase: u64) -> u64 {
+        match self {
+            PagingMode::TwoLevel => dirbase & 0xffff_f000,
+            PagingMode::Pae => dirbase & 0xffff_ffe0,
+            _ => dirbase & PMASK,
+        }
+    }
+
+    /// Virtual address of entry `index` of the top level structure, sign-extended into
+    /// the kernel half in long mode.
+    pub fn top_level_va(self, index: u64) -> u64 {
+        let va = index << self.shift(self.levels());
+        match self {
+            PagingMode::FourLevel if index >= 256 => va | 0xffff_0000_0000_0000,
+            PagingMode::FiveLevel if index >= 256 => va | 0xfe00_0000_0000_0000,
+            _ => va,
+        }
+    }
+
+    /// Where the kernel half of the address space starts.
+    pub fn user_space_end(self) -> u64 {
+        match self {
+            PagingMode::TwoLevel | PagingMode::Pae => 0x8000_0000,
+            PagingMode::FourLevel => USER_SPACE_END,
+            PagingMode::FiveLevel => 0x0100_0000_0000_0000,
+        }
+    }
+}
+
+impl VMBinding {
+    /// The page table format addresses are translated with.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging
+    }
+
+    /// Reads one paging structure entry, 4 or 8 bytes depending on the mode.
+    pub(crate) fn read_table_entry(&self, address: u64) -> Result<u64> {
+        match self.paging.entry_size() {
+            4 => Ok(self.try_read::<u32>(address)? as u64),
+            _ => self.try_read(address),
+        }
+    }
+}
+
+#[test]
+fn test_paging_modes() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    assert_eq!(PagingMode::from_cr4(0x3516f8), PagingMode::FiveLevel);
+    assert_eq!(PagingMode::from_cr4(0x3506f8), PagingMode::FourLevel);
+    assert_eq!(PagingMode::from_dtb(0x1aa000), PagingMode::FourLevel);
+    assert_eq!(PagingMode::from_dtb(0x7f2c_0380), PagingMode::Pae);
+
+    // 0x1000 -> 0x2000 -> 0x3000 -> 0x4000 -> 0x5000, read as the tables of each mode
+    let mut vm = flat_test_binding(&[]);
+    vm.paging = PagingMode::TwoLevel;
+    assert_eq!(vm.try_translate(0x1000, 0x123).unwrap(), 0x3123);
+    // The upper half of the first 8-byte entry is the second PDE
+    assert!(vm.try_translate(0x1000, 0x40_0000).is_err());
+    vm.write(0x1004, &0x80_0083u32.to_le_bytes()).unwrap();
+    assert_eq!(vm.try_translate(0x1000, 0x40_1234).unwrap(), 0x80_1234);
+    // Cached as one 4M page
+    let (hits, misses) = vm.tlb_stats();
+    assert_eq!(vm.try_translate(0x1000, 0x7f_fff0).unwrap(), 0xbf_fff0);
+    assert_eq!(vm.tlb_stats(), (hits + 1, misses));
+    vm.write(0x1004, &0u32.to_le_bytes()).unwrap();
+
+    vm.flush_tlb();
+    vm.paging = PagingMode::Pae;
+    assert_eq!(vm.try_translate(0x1000, 0x123).unwrap(), 0x4123);
+    assert!(vm.try_translate(0x1000, 0x4000_0000).is_err());
+
+    vm.flush_tlb();
+    vm.paging = PagingMode::FiveLevel;
+    assert!(vm.try_translate(0x1000, 0x123).is_err());
+    vm.write(0x4000, &0x20_0083u64.to_le_bytes()).unwrap();
+    assert_eq!(vm.try_translate(0x1000, 0x123).unwrap(), 0x20_0123);
+}
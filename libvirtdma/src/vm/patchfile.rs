@@ -0,0 +1,109 @@
+use crate::error::{Error, Result};
+
+/// A run of patched bytes inside a module, independent of where the module is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModulePatch {
+    pub module: String,
+    pub rva: u64,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+/// Reads an x64dbg `.1337` patch file: a `>module.dll` line followed by one
+/// `RVA:OLD->NEW` line per byte, all in hex. `module:RVA:OLD->NEW` lines are accepted
+/// too. Consecutive bytes of a module are merged into one `ModulePatch`.
+pub fn parse_1337(s: &str) -> Result<Vec<ModulePatch>> {
+    let mut patches: Vec<ModulePatch> = Vec::new();
+    let mut module: Option<String> = None;
+    for (n, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('>') {
+            module = Some(name.trim().to_string());
+            continue;
+        }
+        let bad = |what: &str| Error::Parse(format!("line {}: {} in '{}'", n + 1, what, line));
+        let (location, change) = match line.rfind(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => return Err(bad("no ':'")),
+        };
+        let (name, rva) = match location.rfind(':') {
+            Some(i) => (&location[..i], &location[i + 1..]),
+            None => match &module {
+                Some(m) => (m.as_str(), location),
+                None => return Err(bad("no '>module' line before")),
+            },
+        };
+        let (old, new) = match change.find("->") {
+            Some(i) => (&change[..i], &change[i + 2..]),
+            None => return Err(bad("no '->'")),
+        };
+        let rva = u64::from_str_radix(rva.trim(), 16).map_err(|_| bad("bad RVA"))?;
+        let old = u8::from_str_radix(old.trim(), 16).map_err(|_| bad("bad original byte"))?;
+        let new = u8::from_str_radix(new.trim(), 16).map_err(|_| bad("bad new byte"))?;
+
+        match patches.last_mut() {
+            Some(last) if last.module == name && last.rva + last.patched.len() as u64 == rva => {
+                last.original.push(old);
+                last.patched.push(new);
+            }
+            _ => patches.push(ModulePatch {
+                module: name.to_string(),
+                rva,
+                original: vec![old],
+                patched: vec![new],
+            }),
+        }
+    }
+    Ok(patches)
+}
+
+/// Writes `patches` in x64dbg's `.1337` format, one `>module` section per change of module.
+pub fn format_1337(patches: &[ModulePatch]) -> String {
+    let mut out = String::new();
+    let mut module: Option<&str> = None;
+    for p in patches.iter() {
+        if module != Some(p.module.as_str()) {
+            out.push_str(&format!(">{}\r\n", p.module));
+            module = Some(p.module.as_str());
+        }
+        for (i, (old, new)) in p.original.iter().zip(p.patched.iter()).enumerate() {
+            out.push_str(&format!(
+                "{:016X}:{:02X}->{:02X}\r\n",
+                p.rva + i as u64,
+                old,
+                new
+            ));
+        }
+    }
+    out
+}
+
+#[test]
+fn test_1337_roundtrip() {
+    let s = ">game.exe\r\n00000000000012AB:74->EB\r\n00000000000012AC:05->90\r\n\r\n\
+             user32.dll:1000:CC->C3\r\n";
+    let patches = parse_1337(s).unwrap();
+    assert_eq!(
+        patches,
+        vec![
+            ModulePatch {
+                module: "game.exe".to_string(),
+                rva: 0x12ab,
+                original: vec![0x74, 0x05],
+                patched: vec![0xeb, 0x90],
+            },
+            ModulePatch {
+                module: "user32.dll".to_string(),
+                rva: 0x1000,
+                original: vec![0xcc],
+                patched: vec![0xc3],
+            },
+        ]
+    );
+    assert_eq!(parse_1337(&format_1337(&patches)).unwrap(), patches);
+    assert!(parse_1337("12AB:74->EB").is_err());
+    assert!(parse_1337(">game.exe\n12AB:74-EB").is_err());
+}
@@ -0,0 +1,241 @@
+use crate::error::Result;
+use crate::vm::paging::PagingMode;
+use crate::vm::{VMBinding, PAGE_OFFSET_SIZE};
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const ACCESSED: u64 = 1 << 5;
+const DIRTY: u64 = 1 << 6;
+const LARGE: u64 = 1 << 7;
+const GLOBAL: u64 = 1 << 8;
+// Software bits of a non-present Windows PTE
+const PROTOTYPE: u64 = 1 << 10;
+const TRANSITION: u64 = 1 << 11;
+const NX: u64 = 1 << 63;
+
+/// What a paging structure entry says about the memory it maps, including the
+/// software formats Windows uses for non-present entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PteState {
+    /// Present; `pfn` is the page (or next table) in guest RAM
+    Valid { pfn: u64 },
+    /// Removed from the working set but still in RAM at `pfn`
+    Transition { pfn: u64, protection: u8 },
+    /// Paged out to page file `file`, at page `offset` in it
+    PageFile {
+        file: u8,
+        offset: u64,
+        protection: u8,
+    },
+    /// Shared memory described by the prototype PTE at `address` (a kernel VA; 0 for
+    /// 32-bit non-PAE guests, whose format for it isn't decoded)
+    Prototype { address: u64 },
+    /// Committed but never touched, reads as zeroes
+    DemandZero { protection: u8 },
+    /// An empty entry
+    NotMapped,
+}
+
+impl PteState {
+    /// Decodes an entry of a 64-bit guest.
+    pub fn decode(entry: u64) -> PteState {
+        PteState::decode_for(PagingMode::FourLevel, entry)
+    }
+
+    /// Decodes an entry in the format of a guest using `mode`.
+    pub fn decode_for(mode: PagingMode, entry: u64) -> PteState {
+        let protection = ((entry >> 5) & 0x1f) as u8;
+        let pfn = (entry & mode.frame_mask()) >> PAGE_OFFSET_SIZE;
+        // The page file offset takes the upper 32 bits, the upper 20 without PAE
+        let offset_shift = match mode {
+            PagingMode::TwoLevel => 12,
+            _ => 32,
+        };
+        // PageFileLow: bits 12-15 in the 64-bit layout since Windows 10 1803, bits 1-4
+        // on 32-bit guests
+        let file_shift = match mode {
+            PagingMode::FourLevel | PagingMode::FiveLevel => 12,
+            _ => 1,
+        };
+        if entry & PRESENT != 0 {
+            PteState::Valid { pfn }
+        } else if entry == 0 {
+            PteState::NotMapped
+        } else if entry & PROTOTYPE != 0 {
+            let address = match mode {
+                // 48-bit ProtoAddress in the upper bits, sign-extended like any kernel VA
+                PagingMode::FourLevel | PagingMode::FiveLevel => ((entry as i64) >> 16) as u64,
+                PagingMode::Pae => entry >> 32,
+                PagingMode::TwoLevel => 0,
+            };
+            PteState::Prototype { address }
+        } else if entry & TRANSITION != 0 {
+            PteState::Transition { pfn, protection }
+        } else if entry >> offset_shift != 0 {
+            PteState::PageFile {
+                file: ((entry >> file_shift) & 0xf) as u8,
+                offset: entry >> offset_shift,
+                protection,
+            }
+        } else {
+            PteState::DemandZero { protection }
+        }
+    }
+
+    /// Guest-physical page the entry points to, if the data is in RAM.
+    pub fn pfn(&self) -> Option<u64> {
+        match self {
+            PteState::Valid { pfn } | PteState::Transition { pfn, .. } => Some(*pfn),
+            _ => None,
+        }
+    }
+}
+
+/// One entry read on the way from the DTB to the page.
+#[derive(Debug, Clone)]
+pub struct WalkStep {
+    /// 1 for the PT up to `PagingMode::levels()` for the top level structure
+    pub level: u8,
+    /// Guest-physical address of the entry
+    pub address: u64,
+    pub entry: u64,
+    pub state: PteState,
+    /// A PDPTE or PDE mapping a 1G or 2M page directly
+    pub large: bool,
+}
+
+impl WalkStep {
+    /// The hardware flags of a valid entry, like `P W U A D PS NX`.
+    pub fn flags(&self) -> String {
+        if self.entry & PRESENT == 0 {
+            return String::new();
+        }
+        [
+            (PRESENT, "P"),
+            (WRITABLE, "W"),
+            (USER, "U"),
+            (ACCESSED, "A"),
+            (DIRTY, "D"),
+            (LARGE, "PS"),
+            (GLOBAL, "G"),
+            (NX, "NX"),
+        ]
+        .iter()
+        .filter(|(bit, _)| self.entry & bit != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<&str>>()
+        .join(" ")
+    }
+}
+
+/// The full walk for one virtual address.
+#[derive(Debug, Clone)]
+pub struct Translation {
+    pub dirbase: u64,
+    pub va: u64,
+    /// Every entry read, PML4 first; the last one decides
+    pub steps: Vec<WalkStep>,
+    /// Where the data is in guest RAM, for valid and transition pages
+    pub pa: Option<u64>,
+    pub page_size: u64,
+}
+
+impl VMBinding {
+    /// Walks the page tables for `address` like `try_translate`, in the binding's
+    /// `PagingMode`, but returns every entry on the way, decoded, and stops at the first
+    /// non-present one instead of failing. Transition entries are followed since what
+    /// they map is still in RAM. Only paging structures that can't be read are an error.
+    pub fn translate_detailed(&self, dirbase: u64, address: u64) -> Result<Translation> {
+        let mut translation = Translation {
+            dirbase,
+            va: address,
+            steps: Vec::new(),
+            pa: None,
+            page_size: 1 << PAGE_OFFSET_SIZE,
+        };
+        let mode = self.paging;
+        let mut table = mode.table_base(dirbase);
+        for level in (1..=mode.levels()).rev() {
+            let shift = mode.shift(level);
+            let entry_address = table + mode.entry_size() * mode.index(address, level);
+            let entry = self.read_table_entry(entry_address)?;
+            let state = PteState::decode_for(mode, entry);
+            let large = entry & PRESENT != 0 && mode.large_page(level, entry);
+            translation.steps.push(WalkStep {
+                level,
+                address: entry_address,
+                entry,
+                state,
+                large,
+            });
+            let pfn = match state.pfn() {
+                Some(pfn) => pfn,
+                None => break,
+            };
+            if level == 1 || large {
+                let page_size = 1u64 << shift;
+                translation.page_size = page_size;
+                translation.pa = Some(
+                    ((pfn << PAGE_OFFSET_SIZE) & !(page_size - 1)) + (address & (page_size - 1)),
+                );
+                break;
+            }
+            table = pfn << PAGE_OFFSET_SIZE;
+        }
+        Ok(translation)
+    }
+}
+
+#[test]
+fn test_translate_detailed() {
+    use crate::vm::binding_rw::flat_test_binding;
+
+    let vm = flat_test_binding(&[
+        (0x4008, 0x6000 | TRANSITION | (4 << 5)),
+        (0x4010, (0x1234 << 32) | (2 << 12) | (4 << 5)),
+        (0x4018, 4 << 5),
+        (0x4020, (0xffff_8000_1234_5670u64 << 16) | PROTOTYPE),
+    ]);
+    let leaf = |va: u64| {
+        let t = vm.translate_detailed(0x1000, va).unwrap();
+        (t.steps.last().unwrap().state, t.pa)
+    };
+
+    let t = vm.translate_detailed(0x1000, 0x123).unwrap();
+    assert_eq!(t.steps.len(), 4);
+    assert_eq!(t.steps[0].flags(), "P W");
+    assert_eq!(t.pa, Some(0x5123));
+    assert_eq!(
+        leaf(0x1008),
+        (
+            PteState::Transition {
+                pfn: 6,
+                protection: 4
+            },
+            Some(0x6008)
+        )
+    );
+    assert_eq!(
+        leaf(0x2000),
+        (
+            PteState::PageFile {
+                file: 2,
+                offset: 0x1234,
+                protection: 4
+            },
+            None
+        )
+    );
+    assert_eq!(leaf(0x3000).0, PteState::DemandZero { protection: 4 });
+    assert_eq!(
+        leaf(0x4000).0,
+        PteState::Prototype {
+            address: 0xffff_8000_1234_5670
+        }
+    );
+    assert_eq!(leaf(0x5000).0, PteState::NotMapped);
+    // No PD entry at all for the second 2M
+    let t = vm.translate_detailed(0x1000, 0x20_0000).unwrap();
+    assert_eq!((t.steps.len(), t.pa), (3, None));
+}
@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Page sizes a translation can map, as shifts: 4K, 2M, 4M (32-bit without PAE) and 1G
+const PAGE_SHIFTS: [u64; 4] = [12, 21, 22, 30];
+// Dropped wholesale once full, a walk that touches this many pages won't mind
+const MAX_ENTRIES: usize = 1 << 16;
+const TABLE_PAGE_SHIFT: u64 = 12;
+
+/// Until the guest remaps it, a live translation is assumed good for this long.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+/// Software TLB: virtual to guest-physical translations per DTB, for every page size.
+/// Entries expire after `ttl` (if any), and `flush`/`flush_dtb` drop them on request, as
+/// does a write to one of the paging structures they were read from.
+#[derive(Debug)]
+pub struct Tlb {
+    /// (DTB, page-aligned VA, page shift) -> (page-aligned PA, time of the walk)
+    entries: HashMap<(u64, u64, u64), (u64, Instant)>,
+    /// Guest-physical pages holding the paging structures the entries were read from
+    tables: HashSet<u64>,
+    ttl: Option<Duration>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Tlb {
+        Tlb::new(Some(DEFAULT_TTL))
+    }
+}
+
+impl Tlb {
+    pub fn new(ttl: Option<Duration>) -> Tlb {
+        Tlb {
+            entries: HashMap::new(),
+            tables: HashSet::new(),
+            ttl,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The cached guest-physical address of `va` in the address space of `dtb`.
+    pub fn lookup(&mut self, dtb: u64, va: u64) -> Option<u64> {
+        let now = Instant::now();
+        for shift in PAGE_SHIFTS.iter() {
+            let key = (dtb, va >> shift << shift, *shift);
+            if let Some((pa, filled)) = self.entries.get(&key).copied() {
+                if let Some(ttl) = self.ttl {
+                    if now.duration_since(filled) >= ttl {
+                        self.entries.remove(&key);
+                        continue;
+                    }
+                }
+                self.hits += 1;
+                return Some(pa + (va & ((1 << shift) - 1)));
+            }
+        }
+        self.misses += 1;
+        None
+    }
+
+    /// Remembers that `va` is mapped to `pa` by a page of `1 << shift` bytes, as read from
+    /// the paging structures at `tables`.
+    pub fn insert(&mut self, dtb: u64, va: u64, pa: u64, shift: u64, tables: &[u64]) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.flush();
+        }
+        self.tables
+            .extend(tables.iter().map(|t| t >> TABLE_PAGE_SHIFT));
+        let mask = !((1u64 << shift) - 1);
+        self.entries
+            .insert((dtb, va & mask, shift), (pa & mask, Instant::now()));
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        self.tables.clear();
+    }
+
+    /// Flushes if the guest-physical range written overlaps a paging structure that a
+    /// cached translation was read from. Other writes can't change any translation.
+    pub fn invalidate_write(&mut self, pa: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first = pa >> TABLE_PAGE_SHIFT;
+        let last = pa.saturating_add(len - 1) >> TABLE_PAGE_SHIFT;
+        let hit = if last - first < self.tables.len() as u64 {
+            (first..=last).any(|page| self.tables.contains(&page))
+        } else {
+            self.tables.iter().any(|page| (first..=last).contains(page))
+        };
+        if hit {
+            self.flush();
+        }
+    }
+
+    /// Drops the translations of one address space, e.g. after its process exited.
+    pub fn flush_dtb(&mut self, dtb: u64) {
+        self.entries.retain(|(d, _, _), _| *d != dtb);
+    }
+
+    /// How long entries stay valid; `None` keeps them until flushed (for memory images).
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[test]
+fn test_tlb() {
+    let mut tlb = Tlb::new(None);
+    tlb.insert(0x1000, 0x7ff6_0000_1234, 0x5000, 12, &[0x1000, 0x3000]);
+    tlb.insert(0x1000, 0xffff_f800_0020_0000, 0x4020_0000, 21, &[0x1000]);
+    tlb.insert(0x2000, 0x7ff6_0000_1000, 0x9000, 12, &[0x2000]);
+    assert_eq!(tlb.lookup(0x1000, 0x7ff6_0000_1ff0), Some(0x5ff0));
+    assert_eq!(tlb.lookup(0x1000, 0x7ff6_0000_2000), None);
+    assert_eq!(tlb.lookup(0x1000, 0xffff_f800_0031_2345), Some(0x4031_2345));
+    tlb.flush_dtb(0x1000);
+    assert_eq!(tlb.lookup(0x1000, 0x7ff6_0000_1ff0), None);
+    assert_eq!(tlb.lookup(0x2000, 0x7ff6_0000_1008), Some(0x9008));
+    assert_eq!((tlb.hits, tlb.misses), (3, 2));
+
+    // Only writes to a paging structure drop entries
+    tlb.invalidate_write(0x5000, 0x1000);
+    tlb.invalidate_write(0x8ff8, 8);
+    assert_eq!(tlb.len(), 1);
+    tlb.invalidate_write(0x2ffc, 8);
+    assert!(tlb.is_empty());
+    tlb.insert(0x2000, 0x7ff6_0000_1000, 0x9000, 12, &[0x2000]);
+
+    tlb.set_ttl(Some(Duration::from_secs(0)));
+    assert_eq!(tlb.lookup(0x2000, 0x7ff6_0000_1008), None);
+    assert!(tlb.is_empty());
+}
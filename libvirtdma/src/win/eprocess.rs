@@ -2,6 +2,8 @@
 use crate::win::list_entry::{ListEntry, SingleListEntry};
 use crate::win::misc::*;
 
+pub const KPROCESS_USER_DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x278;
+
 // 0x1 bytes (sizeof)
 #[repr(C)]
 #[derive(Copy, Clone, BitfieldStruct)]
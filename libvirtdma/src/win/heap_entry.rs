@@ -14,7 +14,7 @@ use crate::win::proc_heap_entry::{HeapBlock, HeapBlockOrRegion, ProcessHeapEntry
 use std::ffi::c_void;
 
 //0x2c0 bytes (sizeof)
-#[derive(Clone, Debug)]
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct HEAP {
     pub Segment: HeapSegment,
@@ -66,7 +66,7 @@ pub struct HEAP {
     // struct _HEAP_TUNING_PARAMETERS TuningParameters;                        //0x2b0
 }
 
-#[derive(Clone, Debug)]
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct HeapSegment {
     pub Entry: HEAP_ENTRY,                   //0x0
@@ -1,5 +1,7 @@
 extern crate static_assertions as sa;
 
+use crate::error::{Error, Result};
+
 pub mod pe;
 
 sa::const_assert!(std::mem::size_of::<pe::ImageNtHeaders64>() == 0x108);
@@ -53,6 +55,45 @@ sa::const_assert!(std::mem::size_of::<heap_entry::HEAP>() == 0x2c0);
 // 0xa0 bytes (sizeof) on  Windows 10 | 2016 1809 Redstone 5 (October Update) x64
 sa::const_assert!(std::mem::size_of::<ethread::KldrDataTableEntry>() == 0xa0);
 
+crate::unsafe_impl_pod!(
+    eprocess::PsProtection,
+    eprocess::EPROCESS,
+    eprocess::KPROCESS,
+    ethread::KTHREAD,
+    ethread::ETHREAD,
+    ethread::KldrDataTableEntry,
+    heap_entry::HEAP,
+    heap_entry::HeapSegment,
+    heap_entry::HEAP_ENTRY,
+    heap_entry::HEAP_UNPACKED_ENTRY,
+    heap_entry::HEAP_EXTENDED_ENTRY,
+    list_entry::SingleListEntry,
+    list_entry::ListEntry,
+    misc::GroupAffinity,
+    pe::ImageBaseRelocation,
+    pe::TypeOffset,
+    pe::ImageNtHeaders64,
+    pe::ImageFileHeader,
+    pe::ImageDataDirectory,
+    pe::ImageSectionHeader,
+    pe::ImageOptionalHeader64,
+    peb::PEBFlags,
+    peb::FullPEB,
+    peb_ldr_data::PebLdrData,
+    peb_ldr_data::LdrModule,
+    proc_heap_entry::HeapBlock,
+    proc_heap_entry::HeapRegion,
+    proc_heap_entry::HeapBlockOrRegion,
+    proc_heap_entry::ProcessHeapEntry,
+    teb::ProcessorNumber,
+    teb::GUID,
+    teb::ActivationContextStack,
+    teb::ClientID,
+    teb::NtTIB,
+    teb::TEB,
+    unicode_string::UnicodeString,
+);
+
 #[derive(Clone, Copy, Debug)]
 pub struct Offsets {
     pub apl: i64,
@@ -68,11 +109,11 @@ pub struct Offsets {
 }
 
 impl Offsets {
-    pub fn get_offsets(nt_version: u16, nt_build: u32) -> Option<Offsets> {
+    pub fn get_offsets(nt_version: u16, nt_build: u32) -> Result<Offsets> {
         match nt_version {
             502 => {
                 /* XP SP2 */
-                Some(Offsets {
+                Ok(Offsets {
                     apl: 0xe0,
                     session: 0x260,
                     stack_count: 0xa0,
@@ -104,11 +145,11 @@ impl Offsets {
                     ret.image_file_name = 0x2d8;
                     ret.thread_list_entry = 0x428;
                 }
-                Some(ret)
+                Ok(ret)
             }
             602 => {
                 // Windows 8
-                Some(Offsets {
+                Ok(Offsets {
                     apl: 0x2e8,
                     session: 0x430,
                     stack_count: 0x234,
@@ -124,7 +165,7 @@ impl Offsets {
             }
             603 => {
                 // Windows 8.1
-                Some(Offsets {
+                Ok(Offsets {
                     apl: 0x2e8,
                     session: 0x430,
                     stack_count: 0x234,
@@ -166,9 +207,12 @@ impl Offsets {
                     ret.thread_list_head = 0x5e0;
                     ret.thread_list_entry = 0x4e8;
                 }
-                Some(ret)
+                Ok(ret)
             }
-            _ => None,
+            _ => Err(Error::UnsupportedVersion {
+                nt_version,
+                nt_build,
+            }),
         }
     }
 }